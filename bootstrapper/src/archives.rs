@@ -1,39 +1,459 @@
 use std::{
-    io::Read,
+    fs::File,
+    io::{Cursor, Read, Seek, SeekFrom, Write},
     os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
 };
 
+use bzip2::{read::BzDecoder, write::BzEncoder, Compression as BzLevel};
+use flate2::{read::GzDecoder, Compression as GzLevel, GzBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use serde::{Deserialize, Serialize};
+use xz2::{
+    read::XzDecoder,
+    stream::{Check, LzmaOptions, Stream},
+    write::XzEncoder,
+};
 use zip::ZipArchive;
 
 use crate::sanitize_path;
 
+/// Errors surfaced by [`Archiver::filter_extract`] and the free functions
+/// around it, in place of the `.unwrap()`s they used to raise on a short
+/// read, a truncated entry, or an archive trying to escape its extraction
+/// root. A coordinator can catch this per-source and report which one
+/// failed instead of the whole bootstrap aborting.
+#[derive(Debug, thiserror::Error)]
+pub enum ArchiveError {
+    #[error("io error reading archive: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("zip error: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("could not determine archive format from its contents")]
+    UnsupportedFormat,
+    #[error("truncated or corrupt entry: {0}")]
+    CorruptEntry(String),
+    #[error("rar error: {0}")]
+    Rar(String),
+    #[error("7z error: {0}")]
+    SevenZip(String),
+    #[error("symlink {path:?} targets {target:?}, which escapes the extraction root {root:?}")]
+    UnsafeSymlink {
+        path: PathBuf,
+        target: PathBuf,
+        root: PathBuf,
+    },
+    #[error("extraction filter rejected entry {0:?}: {1}")]
+    Filter(PathBuf, String),
+}
+
+pub type Result<T> = std::result::Result<T, ArchiveError>;
+
+/// Compression/container format of a source archive, sniffed from its URL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+    TarBz2,
+    TarXz,
+    TarZst,
+    Rar,
+    SevenZip,
+}
+
+impl ArchiveKind {
+    /// Guess the archive kind from the tail of a source URL, e.g.
+    /// `https://example.com/foo-1.0.tar.xz` -> `ArchiveKind::TarXz`.
+    pub fn detect(url: &str) -> Option<Self> {
+        if url.ends_with(".zip") {
+            Some(Self::Zip)
+        } else if url.ends_with(".tar.gz") || url.ends_with(".tgz") {
+            Some(Self::TarGz)
+        } else if url.ends_with(".tar.bz2") || url.ends_with(".tbz2") {
+            Some(Self::TarBz2)
+        } else if url.ends_with(".tar.xz") {
+            Some(Self::TarXz)
+        } else if url.ends_with(".tar.zst") || url.ends_with(".tar.zstd") {
+            Some(Self::TarZst)
+        } else if url.ends_with(".tar") {
+            Some(Self::Tar)
+        } else if url.ends_with(".rar") {
+            Some(Self::Rar)
+        } else if url.ends_with(".7z") {
+            Some(Self::SevenZip)
+        } else {
+            None
+        }
+    }
+
+    /// Sniff the format from the archive's own leading magic bytes, for
+    /// sources whose URL doesn't carry a recognisable extension (redirects,
+    /// API-served tarballs, etc).
+    pub fn sniff(data: &[u8]) -> Option<Self> {
+        if data.starts_with(&[0x1f, 0x8b]) {
+            Some(Self::TarGz)
+        } else if data.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Self::TarXz)
+        } else if data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Self::TarZst)
+        } else if data.starts_with(&[0x42, 0x5a, 0x68]) {
+            Some(Self::TarBz2)
+        } else if data.starts_with(&[0x50, 0x4b, 0x03, 0x04]) {
+            Some(Self::Zip)
+        } else if data.len() > 257 + 5 && &data[257..262] == b"ustar" {
+            Some(Self::Tar)
+        } else if data.starts_with(b"Rar!\x1a\x07") {
+            Some(Self::Rar)
+        } else if data.starts_with(&[0x37, 0x7a, 0xbc, 0xaf, 0x27, 0x1c]) {
+            Some(Self::SevenZip)
+        } else {
+            None
+        }
+    }
+}
+
+/// A decompressed tarball is handed to `tar::Archive`, which needs `Seek` to
+/// support the `reset()`/re-iteration `filter_extract` relies on. Small
+/// tarballs stay in memory; anything past [`SPILL_THRESHOLD`] is spilled to a
+/// temporary file instead of holding two copies (compressed + decompressed)
+/// resident at once.
+const SPILL_THRESHOLD: usize = 64 * 1024 * 1024;
+
+pub enum Spill {
+    Mem(Cursor<Vec<u8>>),
+    File(File),
+}
+
+impl Read for Spill {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Spill::Mem(c) => c.read(buf),
+            Spill::File(f) => f.read(buf),
+        }
+    }
+}
+
+impl Seek for Spill {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            Spill::Mem(c) => c.seek(pos),
+            Spill::File(f) => f.seek(pos),
+        }
+    }
+}
+
+fn spill(data: Vec<u8>) -> Result<Spill> {
+    if data.len() <= SPILL_THRESHOLD {
+        Ok(Spill::Mem(Cursor::new(data)))
+    } else {
+        let mut f = tempfile::tempfile()?;
+        f.write_all(&data)?;
+        f.seek(SeekFrom::Start(0))?;
+        Ok(Spill::File(f))
+    }
+}
+
+/// Decode an xz stream with no memory limit, so large rust-style dist
+/// tarballs (which use a wide LZMA2 dictionary) decompress correctly instead
+/// of erroring out against `xz2`'s conservative default memlimit.
+fn xz_decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let stream = xz2::stream::Stream::new_stream_decoder(u64::MAX, 0)
+        .map_err(|e| ArchiveError::CorruptEntry(e.to_string()))?;
+    let mut decoder = XzDecoder::new_stream(data, stream);
+    let mut buf = Vec::new();
+    decoder.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// A concrete, already-opened archive of one of the container formats this
+/// module understands. Kept as an enum rather than `Box<dyn Archiver>`
+/// because [`Archiver::filter_extract`] is generic over its filter closure,
+/// which isn't object-safe; matching on the enum still gives callers one type
+/// to hold regardless of which format `open_archive` detected.
+pub enum Archive {
+    Zip(ZipArchive<Spill>),
+    Tar(tar::Archive<Spill>),
+    Rar(RarArchive),
+    SevenZip(SevenZipArchive),
+}
+
+impl Archiver for Archive {
+    fn filter_extract(
+        &mut self,
+        target: &Path,
+        options: &ExtractOptions,
+        filter: impl Fn(&Path, &Option<PathBuf>) -> Result<Option<PathBuf>>,
+    ) -> Result<()> {
+        match self {
+            Archive::Zip(z) => z.filter_extract(target, options, filter),
+            Archive::Tar(t) => t.filter_extract(target, options, filter),
+            Archive::Rar(r) => r.filter_extract(target, options, filter),
+            Archive::SevenZip(s) => s.filter_extract(target, options, filter),
+        }
+    }
+}
+
+/// A `.rar` archive, kept as the backing temp file `unrar` reads from
+/// rather than an in-memory buffer: the library shells out to the unrar
+/// FFI by path, and unlike `tar`/`zip` doesn't support extracting from an
+/// arbitrary `Read + Seek`.
+pub struct RarArchive {
+    file: tempfile::NamedTempFile,
+}
+
+/// A `.7z` archive, held as a temp file for the same reason as
+/// [`RarArchive`]: `sevenz-rust` reads its central directory from a
+/// concrete file path.
+pub struct SevenZipArchive {
+    file: tempfile::NamedTempFile,
+}
+
+fn spill_to_tempfile(data: &[u8]) -> Result<tempfile::NamedTempFile> {
+    let mut f = tempfile::NamedTempFile::new()?;
+    f.write_all(data)?;
+    f.flush()?;
+    Ok(f)
+}
+
+/// Decompress `data` per `kind` into a seekable [`Spill`] buffer and wrap it
+/// in the matching concrete reader, ready for [`Archiver::filter_extract`].
+pub fn decode_archive(kind: ArchiveKind, data: &[u8]) -> Result<Archive> {
+    Ok(match kind {
+        ArchiveKind::Zip => Archive::Zip(ZipArchive::new(spill(data.to_vec())?)?),
+        ArchiveKind::Tar => Archive::Tar(tar::Archive::new(spill(data.to_vec())?)),
+        ArchiveKind::TarGz => {
+            let mut buf = Vec::new();
+            GzDecoder::new(data).read_to_end(&mut buf)?;
+            Archive::Tar(tar::Archive::new(spill(buf)?))
+        }
+        ArchiveKind::TarBz2 => {
+            let mut buf = Vec::new();
+            BzDecoder::new(data).read_to_end(&mut buf)?;
+            Archive::Tar(tar::Archive::new(spill(buf)?))
+        }
+        ArchiveKind::TarXz => {
+            let buf = xz_decompress(data)?;
+            Archive::Tar(tar::Archive::new(spill(buf)?))
+        }
+        ArchiveKind::TarZst => {
+            let mut buf = Vec::new();
+            zstd::Decoder::new(data)?.read_to_end(&mut buf)?;
+            Archive::Tar(tar::Archive::new(spill(buf)?))
+        }
+        ArchiveKind::Rar => Archive::Rar(RarArchive {
+            file: spill_to_tempfile(data)?,
+        }),
+        ArchiveKind::SevenZip => Archive::SevenZip(SevenZipArchive {
+            file: spill_to_tempfile(data)?,
+        }),
+    })
+}
+
+/// Dispatch on `kind` and run the matching [`Archiver`] impl over `data`,
+/// so callers don't need to know whether a source shipped as a zip or one of
+/// the various flavours of tar.
+pub fn extract_source(
+    kind: ArchiveKind,
+    data: &[u8],
+    target: &Path,
+    options: &ExtractOptions,
+    filter: impl Fn(&Path, &Option<PathBuf>) -> Result<Option<PathBuf>>,
+) -> Result<()> {
+    decode_archive(kind, data)?.filter_extract(target, options, filter)
+}
+
+/// Per-recipe tunables for the `xz` [`Compression`] variant. A wider
+/// `dict_size` trades memory for a smaller package; `preset` is the usual
+/// xz effort-level knob (0-9).
+#[derive(Debug, Deserialize, Serialize, Clone, Copy)]
+pub struct XzSettings {
+    #[serde(default = "_default_xz_dict_size")]
+    pub dict_size: u32,
+    #[serde(default = "_default_xz_preset")]
+    pub preset: u32,
+}
+
+impl Default for XzSettings {
+    fn default() -> Self {
+        Self {
+            dict_size: _default_xz_dict_size(),
+            preset: _default_xz_preset(),
+        }
+    }
+}
+
+fn _default_xz_dict_size() -> u32 {
+    64 * 1024 * 1024
+}
+
+fn _default_xz_preset() -> u32 {
+    9
+}
+
+/// How a recipe's final packaged artefact tar is compressed before it's
+/// hashed and shipped. Matches the handful of formats [`ArchiveKind`]
+/// already knows how to read back in, so a built package can be fed
+/// straight back in as a source for another recipe.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+}
+
+/// Compress an already-built package tar per `compression`, so the bytes
+/// that get hashed and shipped are the compressed ones. Every encoder is
+/// pinned to a single deterministic stream - no multithreaded splitting, no
+/// embedded timestamps (the gzip header's mtime is forced to 0) - so the
+/// same tar always compresses to the same bytes regardless of which machine
+/// built it.
+pub fn compress_tar(data: &[u8], compression: Compression, xz: XzSettings) -> Vec<u8> {
+    match compression {
+        Compression::None => data.to_vec(),
+        Compression::Gzip => {
+            let mut encoder = GzBuilder::new().mtime(0).write(Vec::new(), GzLevel::best());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        }
+        Compression::Bzip2 => {
+            let mut encoder = BzEncoder::new(Vec::new(), BzLevel::best());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        }
+        Compression::Xz => {
+            let mut options = LzmaOptions::new_preset(xz.preset).unwrap();
+            options.dict_size(xz.dict_size);
+            // A single stream/block from a non-multithreaded encoder, so
+            // block boundaries can't be chosen nondeterministically.
+            let stream = Stream::new_stream_encoder(&options, Check::Crc32).unwrap();
+            let mut encoder = XzEncoder::new_stream(Vec::new(), stream);
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        }
+        Compression::Zstd => {
+            let mut encoder = zstd::Encoder::new(Vec::new(), 19).unwrap();
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        }
+    }
+}
+
+/// Reverse of [`compress_tar`]: recover the raw tar bytes from an already
+/// packaged artefact. Used by `--verify-reproducible` to diff two builds'
+/// packages entry-by-entry instead of just comparing opaque compressed
+/// bytes.
+pub fn decompress_tar(data: &[u8], compression: Compression) -> Vec<u8> {
+    match compression {
+        Compression::None => data.to_vec(),
+        Compression::Gzip => {
+            let mut buf = Vec::new();
+            GzDecoder::new(data).read_to_end(&mut buf).unwrap();
+            buf
+        }
+        Compression::Bzip2 => {
+            let mut buf = Vec::new();
+            BzDecoder::new(data).read_to_end(&mut buf).unwrap();
+            buf
+        }
+        Compression::Xz => xz_decompress(data).unwrap(),
+        Compression::Zstd => {
+            let mut buf = Vec::new();
+            zstd::Decoder::new(data)
+                .unwrap()
+                .read_to_end(&mut buf)
+                .unwrap();
+            buf
+        }
+    }
+}
+
+/// Sniff `data`'s container format from its magic bytes and open it, without
+/// the caller needing to declare up front whether a recipe's source is a zip
+/// or one of the tar variants.
+pub fn open_archive_reader(mut reader: impl Read) -> Result<Archive> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    let kind = ArchiveKind::sniff(&data).ok_or(ArchiveError::UnsupportedFormat)?;
+    decode_archive(kind, &data)
+}
+
+pub fn open_archive(path: impl AsRef<Path>) -> Result<Archive> {
+    open_archive_reader(File::open(path)?)
+}
+
+/// Metadata-handling policy for the [`Archiver`] backends. Bootstrapping a
+/// reproducible rootfs needs deterministic, controllable extraction rather
+/// than whatever a format's defaults happen to restore. The tar backend
+/// applies every field; zip and 7z apply `overwrite`, `preserve_mtime` and
+/// `preserve_permissions` only; the RAR backend applies only `password`.
+#[derive(Debug, Clone)]
+pub struct ExtractOptions {
+    pub preserve_ownerships: bool,
+    pub preserve_mtime: bool,
+    pub unpack_xattrs: bool,
+    pub preserve_permissions: bool,
+    pub overwrite: bool,
+    /// Password for a password-protected RAR archive. Ignored by every
+    /// other backend.
+    pub password: Option<String>,
+}
+
+impl Default for ExtractOptions {
+    fn default() -> Self {
+        Self {
+            preserve_ownerships: false,
+            preserve_mtime: true,
+            unpack_xattrs: false,
+            preserve_permissions: true,
+            overwrite: true,
+            password: None,
+        }
+    }
+}
+
 pub trait Archiver {
     fn filter_extract(
         &mut self,
         target: &Path,
-        filter: impl Fn(&Path, &Option<PathBuf>) -> Option<PathBuf>,
-    );
+        options: &ExtractOptions,
+        filter: impl Fn(&Path, &Option<PathBuf>) -> Result<Option<PathBuf>>,
+    ) -> Result<()>;
 }
 
 impl<R: std::io::Read + std::io::Seek> Archiver for tar::Archive<R> {
     fn filter_extract(
         &mut self,
         target: &Path,
-        filter: impl Fn(&Path, &Option<PathBuf>) -> Option<PathBuf>,
-    ) {
-        let mut itr = self.entries().unwrap();
+        options: &ExtractOptions,
+        filter: impl Fn(&Path, &Option<PathBuf>) -> Result<Option<PathBuf>>,
+    ) -> Result<()> {
+        self.set_overwrite(options.overwrite);
+        self.set_preserve_permissions(options.preserve_permissions);
+        self.set_preserve_mtime(options.preserve_mtime);
+        self.set_unpack_xattrs(options.unpack_xattrs);
+        self.set_preserve_ownerships(options.preserve_ownerships);
+
+        let mut itr = self.entries()?;
         let mut prefix = Some(
-            PathBuf::from(itr.next().unwrap().unwrap().path().unwrap())
-                .ancestors()
-                .next()
-                .unwrap()
-                .to_owned(),
+            PathBuf::from(
+                itr.next()
+                    .ok_or_else(|| ArchiveError::CorruptEntry("empty archive".into()))??
+                    .path()?,
+            )
+            .ancestors()
+            .next()
+            .unwrap()
+            .to_owned(),
         );
 
         for i in itr {
             if Some(
-                PathBuf::from(i.unwrap().path().unwrap())
+                PathBuf::from(i?.path()?)
                     .ancestors()
                     .next()
                     .unwrap()
@@ -45,15 +465,29 @@ impl<R: std::io::Read + std::io::Seek> Archiver for tar::Archive<R> {
             }
         }
         self.reset();
-        for i in self.entries().unwrap() {
-            let mut i = i.unwrap();
-            let target_path = filter(&i.path().unwrap(), &prefix);
+        for i in self.entries()? {
+            let mut i = i?;
+            let target_path = filter(&i.path()?, &prefix)?;
             if let Some(path) = target_path {
                 let target_path = target.join(sanitize_path(&path));
-                std::fs::create_dir_all(target_path.parent().unwrap()).unwrap();
-                i.unpack(target_path).unwrap();
+                std::fs::create_dir_all(target_path.parent().unwrap())?;
+                if i.header().entry_type().is_symlink() {
+                    let link_name = i.link_name()?.ok_or_else(|| {
+                        ArchiveError::CorruptEntry("symlink with no target".into())
+                    })?;
+                    let link_dir = target_path.parent().unwrap();
+                    if resolve_symlink_target(target, link_dir, &link_name).is_none() {
+                        return Err(ArchiveError::UnsafeSymlink {
+                            path: target_path,
+                            target: link_name.into_owned(),
+                            root: target.to_owned(),
+                        });
+                    }
+                }
+                i.unpack(target_path)?;
             }
         }
+        Ok(())
     }
 }
 
@@ -61,10 +495,11 @@ impl<R: std::io::Read + std::io::Seek> Archiver for ZipArchive<R> {
     fn filter_extract(
         &mut self,
         target: &Path,
-        filter: impl Fn(&Path, &Option<PathBuf>) -> Option<PathBuf>,
-    ) {
+        options: &ExtractOptions,
+        filter: impl Fn(&Path, &Option<PathBuf>) -> Result<Option<PathBuf>>,
+    ) -> Result<()> {
         let mut prefix = Some(
-            PathBuf::from(self.by_index(0).unwrap().name())
+            PathBuf::from(self.by_index(0)?.name())
                 .ancestors()
                 .next()
                 .unwrap()
@@ -72,7 +507,7 @@ impl<R: std::io::Read + std::io::Seek> Archiver for ZipArchive<R> {
         );
         for _ in 1..self.len() {
             if Some(
-                PathBuf::from(self.by_index(0).unwrap().name())
+                PathBuf::from(self.by_index(0)?.name())
                     .ancestors()
                     .next()
                     .unwrap()
@@ -84,48 +519,297 @@ impl<R: std::io::Read + std::io::Seek> Archiver for ZipArchive<R> {
             }
         }
         for i in 0..self.len() {
-            let mut i = self.by_index(i).unwrap();
-            let target_path = filter(&PathBuf::from(i.name()), &prefix);
+            let mut i = self.by_index(i)?;
+            let target_path = filter(&PathBuf::from(i.name()), &prefix)?;
             if let Some(path) = target_path {
                 let target_path = target.join(sanitize_path(&path));
                 if i.is_dir() {
-                    std::fs::create_dir_all(&target_path).unwrap();
+                    std::fs::create_dir_all(&target_path)?;
                 }
                 if i.is_file() {
-                    std::fs::create_dir_all(target_path.parent().unwrap()).unwrap();
-                    let mut outfile = std::fs::File::create(&target_path).unwrap();
-                    std::io::copy(&mut i, &mut outfile).unwrap();
+                    if !options.overwrite && target_path.exists() {
+                        continue;
+                    }
+                    std::fs::create_dir_all(target_path.parent().unwrap())?;
+                    let mtime = options.preserve_mtime.then(|| i.last_modified());
+                    let mut outfile = std::fs::File::create(&target_path)?;
+                    std::io::copy(&mut i, &mut outfile)?;
+                    if let Some(Ok(mtime)) = mtime.map(|m| m.to_time()) {
+                        let ft = filetime::FileTime::from_unix_time(mtime.unix_timestamp(), 0);
+                        let _ = filetime::set_file_mtime(&target_path, ft);
+                    }
                 }
                 if i.is_symlink() {
                     let mut sl = String::new();
-                    i.read_to_string(&mut sl).unwrap();
-                    if sl.starts_with("..") {
-                        std::os::unix::fs::symlink(sl, &target_path).unwrap();
-                    } else if !sl.starts_with("/") {
-                        std::os::unix::fs::symlink(sl, &target_path).unwrap();
-                    } else {
-                        todo!("{:?}", sl)
+                    i.read_to_string(&mut sl)?;
+                    let link_dir = target_path.parent().unwrap();
+                    if resolve_symlink_target(target, link_dir, Path::new(&sl)).is_none() {
+                        return Err(ArchiveError::UnsafeSymlink {
+                            path: target_path,
+                            target: PathBuf::from(sl),
+                            root: target.to_owned(),
+                        });
                     }
-                } else {
+                    std::os::unix::fs::symlink(sl, &target_path)?;
+                } else if options.preserve_permissions {
                     if let Some(mode) = i.unix_mode() {
                         std::fs::set_permissions(
                             target_path,
                             std::fs::Permissions::from_mode(mode),
-                        )
-                        .unwrap();
+                        )?;
                     }
                 }
+                // Zip entries don't carry unix ownership the way tar headers
+                // do, and the de-facto xattr extension is rarely populated by
+                // the archivers recipes pull from, so `preserve_ownerships`
+                // and `unpack_xattrs` are no-ops for this backend.
             }
         }
+        Ok(())
     }
 }
 
+impl Archiver for RarArchive {
+    fn filter_extract(
+        &mut self,
+        target: &Path,
+        options: &ExtractOptions,
+        filter: impl Fn(&Path, &Option<PathBuf>) -> Result<Option<PathBuf>>,
+    ) -> Result<()> {
+        let open = |path: &Path| {
+            let mut archive = unrar::Archive::new(path);
+            if let Some(password) = &options.password {
+                archive = archive.with_password(password);
+            }
+            archive
+                .open_for_processing()
+                .map_err(|e| ArchiveError::Rar(e.to_string()))
+        };
+
+        // First pass over the entry list to find the common top-level
+        // directory, the same `clean_root` heuristic tar/zip apply; `unrar`
+        // only offers forward iteration, so a second, separate open drives
+        // the actual extraction below.
+        let mut names = Vec::new();
+        let mut cursor = open(self.file.path())?;
+        while let Some(header) = cursor
+            .read_header()
+            .map_err(|e| ArchiveError::Rar(e.to_string()))?
+        {
+            names.push(PathBuf::from(header.entry().filename.clone()));
+            cursor = header
+                .skip()
+                .map_err(|e| ArchiveError::Rar(e.to_string()))?;
+        }
+        let mut prefix = names
+            .first()
+            .map(|p| p.ancestors().next().unwrap().to_owned());
+        for p in &names {
+            if Some(p.ancestors().next().unwrap().to_owned()) != prefix {
+                prefix = None;
+                break;
+            }
+        }
+
+        let mut cursor = open(self.file.path())?;
+        while let Some(header) = cursor
+            .read_header()
+            .map_err(|e| ArchiveError::Rar(e.to_string()))?
+        {
+            let entry_path = PathBuf::from(header.entry().filename.clone());
+            let target_path = filter(&entry_path, &prefix)?;
+            cursor = match target_path {
+                Some(path) if header.entry().is_directory() => {
+                    let target_path = target.join(sanitize_path(&path));
+                    std::fs::create_dir_all(&target_path)?;
+                    header
+                        .skip()
+                        .map_err(|e| ArchiveError::Rar(e.to_string()))?
+                }
+                Some(path) => {
+                    let target_path = target.join(sanitize_path(&path));
+                    std::fs::create_dir_all(target_path.parent().unwrap())?;
+                    let cursor = header
+                        .extract_to(&target_path)
+                        .map_err(|e| ArchiveError::Rar(e.to_string()))?;
+                    // `unrar` extracts a symlink entry straight onto disk rather
+                    // than handing us its link text up front the way tar/zip do,
+                    // so the containment check has to happen after the fact: if
+                    // it escapes `target`, undo it before moving on to the next
+                    // entry.
+                    if target_path.is_symlink() {
+                        let link_text = std::fs::read_link(&target_path)?;
+                        let link_dir = target_path.parent().unwrap();
+                        if resolve_symlink_target(target, link_dir, &link_text).is_none() {
+                            let _ = std::fs::remove_file(&target_path);
+                            return Err(ArchiveError::UnsafeSymlink {
+                                path: target_path,
+                                target: link_text,
+                                root: target.to_owned(),
+                            });
+                        }
+                    }
+                    cursor
+                }
+                None => header
+                    .skip()
+                    .map_err(|e| ArchiveError::Rar(e.to_string()))?,
+            };
+        }
+        Ok(())
+    }
+}
+
+/// 7z has no dedicated symlink entry type - archivers that store one (e.g.
+/// `p7zip` on Linux) smuggle the unix `st_mode` into the high 16 bits of
+/// `attributes`, the same convention zip's `external_attr` uses, tagged by
+/// bit 15 (`0x8000`) of the low half, which marks the field as present at
+/// all.
+const SEVENZIP_UNIX_ATTR_PRESENT: u32 = 0x8000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFMT: u32 = 0o170000;
+
+fn sevenzip_unix_mode(attributes: u32) -> Option<u32> {
+    (attributes & SEVENZIP_UNIX_ATTR_PRESENT != 0).then(|| attributes >> 16)
+}
+
+impl Archiver for SevenZipArchive {
+    fn filter_extract(
+        &mut self,
+        target: &Path,
+        options: &ExtractOptions,
+        filter: impl Fn(&Path, &Option<PathBuf>) -> Result<Option<PathBuf>>,
+    ) -> Result<()> {
+        let mut reader =
+            sevenz_rust::SevenZReader::open(self.file.path(), sevenz_rust::Password::empty())
+                .map_err(|e| ArchiveError::SevenZip(e.to_string()))?;
+
+        let names: Vec<PathBuf> = reader
+            .archive()
+            .files
+            .iter()
+            .map(|f| PathBuf::from(f.name()))
+            .collect();
+        let mut prefix = names
+            .first()
+            .map(|p| p.ancestors().next().unwrap().to_owned());
+        for p in &names {
+            if Some(p.ancestors().next().unwrap().to_owned()) != prefix {
+                prefix = None;
+                break;
+            }
+        }
+
+        let mut first_err: Option<ArchiveError> = None;
+        reader
+            .for_each_entries(|entry, entry_reader| {
+                let name = PathBuf::from(entry.name());
+                match filter(&name, &prefix) {
+                    Ok(Some(path)) => {
+                        let target_path = target.join(sanitize_path(&path));
+                        let result = (|| -> Result<()> {
+                            if entry.is_directory() {
+                                std::fs::create_dir_all(&target_path)?;
+                                return Ok(());
+                            }
+                            if !options.overwrite && target_path.exists() {
+                                return Ok(());
+                            }
+                            std::fs::create_dir_all(target_path.parent().unwrap())?;
+
+                            let mode = sevenzip_unix_mode(entry.attributes());
+                            if mode.map(|m| m & S_IFMT == S_IFLNK).unwrap_or(false) {
+                                let mut link_text = String::new();
+                                entry_reader.read_to_string(&mut link_text)?;
+                                let link_dir = target_path.parent().unwrap();
+                                if resolve_symlink_target(target, link_dir, Path::new(&link_text))
+                                    .is_none()
+                                {
+                                    return Err(ArchiveError::UnsafeSymlink {
+                                        path: target_path,
+                                        target: PathBuf::from(link_text),
+                                        root: target.to_owned(),
+                                    });
+                                }
+                                std::os::unix::fs::symlink(link_text, &target_path)?;
+                                return Ok(());
+                            }
+
+                            let mut outfile = std::fs::File::create(&target_path)?;
+                            std::io::copy(entry_reader, &mut outfile)?;
+                            if options.preserve_permissions {
+                                if let Some(mode) = mode {
+                                    std::fs::set_permissions(
+                                        &target_path,
+                                        std::fs::Permissions::from_mode(mode),
+                                    )?;
+                                }
+                            }
+                            if options.preserve_mtime {
+                                let mtime = entry.last_modified_date();
+                                let ft = filetime::FileTime::from_unix_time(mtime.timestamp(), 0);
+                                let _ = filetime::set_file_mtime(&target_path, ft);
+                            }
+                            Ok(())
+                        })();
+                        if let Err(e) = result {
+                            first_err.get_or_insert(e);
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        first_err.get_or_insert(e);
+                    }
+                }
+                Ok(true)
+            })
+            .map_err(|e| ArchiveError::SevenZip(e.to_string()))?;
+
+        if let Some(e) = first_err {
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+/// Resolve a symlink entry's literal link text against the directory that
+/// will contain it, the same way the kernel would once `target` is chrooted
+/// to `/`: an absolute target is re-rooted under `target`, a relative one is
+/// joined onto `link_dir`. Returns `None` if, after cleaning `..`
+/// components, the result would still land outside `target` - the zip-slip
+/// class of escape `sanitize_path` already guards against for entry names,
+/// applied here to entry *contents* instead.
+fn resolve_symlink_target(target: &Path, link_dir: &Path, raw: &Path) -> Option<PathBuf> {
+    let candidate = if raw.is_absolute() {
+        target.join(sanitize_path(raw))
+    } else {
+        link_dir.join(raw)
+    };
+    let cleaned = path_clean::clean(&candidate);
+    cleaned.starts_with(target).then_some(cleaned)
+}
+
+/// Build a single `.gitignore`-style matcher out of `patterns`: each entry
+/// is fed to [`GitignoreBuilder::add_line`], so a bare pattern (e.g.
+/// `src/**/*.rs`) marks matching paths for exclusion and a `!`-prefixed one
+/// (e.g. `!src/tests/**`) negates an earlier exclusion for the paths it
+/// matches - the same combined include/exclude-with-negation semantics as a
+/// real `.gitignore` or `.dockerignore`.
+fn build_matcher(patterns: &[String]) -> Gitignore {
+    let mut builder = GitignoreBuilder::new("");
+    for pattern in patterns {
+        builder.add_line(None, pattern).unwrap();
+    }
+    builder.build().unwrap()
+}
+
 pub fn source_extract_filter<'a>(
     from: &'a Path,
     to: &'a Path,
     include: &'a Option<Vec<String>>,
     clean_root: bool,
-) -> impl Fn(&Path, &Option<PathBuf>) -> Option<PathBuf> + 'a {
+) -> impl Fn(&Path, &Option<PathBuf>) -> Result<Option<PathBuf>> + 'a {
+    let matcher = include.as_ref().map(|patterns| build_matcher(patterns));
     move |p: &Path, prefix: &Option<PathBuf>| {
         print!(
             "{:?} {:?} {:?} {:?} {:?} {} ->",
@@ -134,7 +818,8 @@ pub fn source_extract_filter<'a>(
 
         let p = if clean_root {
             if let Some(prefix) = prefix {
-                p.strip_prefix(prefix).unwrap()
+                p.strip_prefix(prefix)
+                    .map_err(|e| ArchiveError::Filter(p.to_owned(), e.to_string()))?
             } else {
                 p
             }
@@ -142,22 +827,21 @@ pub fn source_extract_filter<'a>(
             p
         };
 
-        if let Some(include) = include {
-            if !include
-                .iter()
-                .any(|x| p.starts_with(sanitize_path(&PathBuf::from(x))))
-            {
+        if let Some(matcher) = &matcher {
+            if matcher.matched(p, false).is_ignore() {
                 println!("None");
-                return None;
+                return Ok(None);
             }
         }
 
         let p = sanitize_path(p);
-        let p = p.strip_prefix(sanitize_path(from)).unwrap();
+        let p = p
+            .strip_prefix(sanitize_path(from))
+            .map_err(|e| ArchiveError::Filter(p.clone(), e.to_string()))?;
         let p = sanitize_path(p);
         let p = sanitize_path(to).join(p);
         let p = sanitize_path(&p);
         println!(" {:?}", p);
-        Some(p)
+        Ok(Some(p))
     }
 }
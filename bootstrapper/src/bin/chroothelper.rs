@@ -1,6 +1,7 @@
 use std::{collections::BTreeMap, ffi::OsString, os::unix::ffi::OsStringExt, path::PathBuf};
 
 use base64::{engine::general_purpose::URL_SAFE, Engine};
+use bootstrapper::recipe_lang::StepAttrs;
 
 fn main() {
     let mut args = std::env::args();
@@ -15,19 +16,34 @@ fn main() {
         serde_yaml::from_slice(&URL_SAFE.decode(args.next().unwrap()).unwrap()).unwrap();
     let environ: BTreeMap<String, String> =
         serde_yaml::from_slice(&URL_SAFE.decode(args.next().unwrap()).unwrap()).unwrap();
+    let attrs: StepAttrs =
+        serde_yaml::from_slice(&URL_SAFE.decode(args.next().unwrap()).unwrap()).unwrap();
     assert!(args.next().is_none());
-    std::os::unix::fs::chroot(builddir).unwrap();
-    std::env::set_current_dir("/").unwrap();
-    println!("CD {:?}", chdir);
-    std::env::set_current_dir(chdir).unwrap();
+
+    if !attrs.allow_network {
+        // Isolate the step in its own network namespace, which starts with
+        // nothing but a down loopback interface - so it can't reach out,
+        // without needing a full veth/iptables setup for the common case of
+        // "this step has no business touching the network".
+        nix::sched::unshare(nix::sched::CloneFlags::CLONE_NEWNET).unwrap();
+    }
+    if attrs.no_chroot {
+        println!("CD {:?} (no-chroot)", builddir.join(&chdir));
+        std::env::set_current_dir(builddir.join(&chdir)).unwrap();
+    } else {
+        std::os::unix::fs::chroot(builddir).unwrap();
+        std::env::set_current_dir("/").unwrap();
+        println!("CD {:?}", chdir);
+        std::env::set_current_dir(chdir).unwrap();
+    }
     let command_executable = command.remove(0);
     println!("RUN {:?}", command_executable);
-    assert!(std::process::Command::new(command_executable)
+    let status = std::process::Command::new(command_executable)
         .args(command)
         .envs(environ)
         .spawn()
         .unwrap()
         .wait()
-        .unwrap()
-        .success())
+        .unwrap();
+    std::process::exit(status.code().unwrap_or(1));
 }
@@ -1,8 +1,12 @@
 use bootstrapper::{
-    env_substitute, network::{
-        finish_deps, finish_overlays, finish_sources, write_dep, write_envs, write_overlay,
-        write_source,
-    }, recipe::{get_depd_hash, get_equiv_hash, NamedRecipeVersion, RecipeVersion, SOURCES}, source::{fetch_source, source_path}, WorkerStatus
+    network::{
+        finish_deps, finish_overlays, finish_sources, read_build_failed, read_capabilities,
+        read_status_update, write_dep, write_envs, write_overlay, write_source, StatusUpdate,
+        WorkerCapabilities,
+    },
+    recipe::{get_depd_hash, get_equiv_hash, NamedRecipeVersion, RecipeVersion, SOURCES},
+    source::{self, fetch_source},
+    WorkerStatus,
 };
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use lazy_static::lazy_static;
@@ -11,14 +15,31 @@ use std::{
     collections::{BTreeMap, BTreeSet},
     fs::File,
     io::{Read, Write},
-    net::{TcpListener, TcpStream},
-    path::PathBuf,
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::Duration,
 };
 use walkdir::WalkDir;
 
 lazy_static! {
     static ref WORK_QUEUE: atomic_queue::Queue<String> = atomic_queue::bounded(10);
+    /// Pending `--verify` double-builds, keyed by the nonce embedded in their
+    /// `verify:name:version:nonce` `WORK_QUEUE` entry, so a handler thread
+    /// can hand its result back to whichever scheduler-loop call requested
+    /// it instead of storing it as a dep directly.
+    static ref RESULT_CHANNELS: Mutex<BTreeMap<String, mpsc::Sender<Result<(String, Vec<u8>), String>>>> =
+        Mutex::new(BTreeMap::new());
+    /// Capabilities asserted by every currently-connected worker, keyed by
+    /// its peer address. Consulted by `claim_ready_build` (to route work to
+    /// a compatible worker) and `report_capability_gap` (to explain why a
+    /// ready node isn't being dispatched).
+    static ref WORKERS: Mutex<BTreeMap<SocketAddr, WorkerCapabilities>> = Mutex::new(BTreeMap::new());
 }
+static VERIFY_NONCE: AtomicU64 = AtomicU64::new(0);
 
 fn ready_to_build(
     deptree: &BTreeMap<(String, String), BTreeSet<(String, String)>>,
@@ -38,6 +59,53 @@ fn finish_dep(
     });
 }
 
+/// Claims one node off `buildable` that `caps` can actually build, moving it
+/// straight into `in_flight` so no other handler thread claims it too.
+/// Unlike `WORK_QUEUE` (a blind FIFO any worker can pop from), this is used
+/// for normal dispatch precisely because it's capability-aware: an
+/// incompatible worker must never be handed work it can't build.
+fn claim_ready_build(
+    buildable: &Mutex<BTreeSet<(String, String)>>,
+    in_flight: &Mutex<BTreeSet<(String, String)>>,
+    caps: &WorkerCapabilities,
+) -> Option<(String, String)> {
+    let mut buildable_guard = buildable.lock().unwrap();
+    let candidate = buildable_guard
+        .iter()
+        .find(|dep| {
+            NamedRecipeVersion::load_by_target_version(&dep.0, &dep.1).compatible_with(caps)
+        })
+        .cloned()?;
+    buildable_guard.remove(&candidate);
+    drop(buildable_guard);
+    in_flight.lock().unwrap().insert(candidate.clone());
+    Some(candidate)
+}
+
+/// Logs once (not on every scheduler tick) when a ready, not-yet-prebuilt
+/// node has no currently-connected worker able to build it, and clears that
+/// logged state again as soon as a compatible worker appears.
+fn report_capability_gap(
+    to_build: &(String, String),
+    gap_logged: &Mutex<BTreeSet<(String, String)>>,
+) {
+    let recipe = NamedRecipeVersion::load_by_target_version(&to_build.0, &to_build.1);
+    let has_compatible = WORKERS
+        .lock()
+        .unwrap()
+        .values()
+        .any(|caps| recipe.compatible_with(caps));
+    let mut gap_logged_guard = gap_logged.lock().unwrap();
+    if has_compatible {
+        gap_logged_guard.remove(to_build);
+    } else if gap_logged_guard.insert(to_build.clone()) {
+        println!(
+            " No connected worker can build {:?} (needs arch={:?}, tools={:?})",
+            to_build, recipe.arch, recipe.requires
+        );
+    }
+}
+
 fn dep_path(hash: &str) -> PathBuf {
     PathBuf::from("build-cache")
         .join("build")
@@ -46,33 +114,244 @@ fn dep_path(hash: &str) -> PathBuf {
         .join(hash)
 }
 
+/// Look up a previously-built, reproducibility-verified artefact on a remote
+/// cache, keyed directly by the recipe's `depd_hash` (the dependency tree
+/// hash, before it's been resolved to a local `equiv.sled` entry).
+fn remote_equiv_hash(depd_hash: &str) -> Option<String> {
+    for cache in bootstrapper::source::cache_endpoints() {
+        let url = format!("{}/equiv/{}", cache.trim_end_matches('/'), depd_hash);
+        if let Ok(resp) = reqwest::blocking::get(&url) {
+            if resp.status().is_success() {
+                if let Ok(text) = resp.text() {
+                    return Some(text.trim().to_owned());
+                }
+            }
+        }
+    }
+    None
+}
+
+fn remote_artefact(equiv_hash: &str) -> Option<Vec<u8>> {
+    for cache in bootstrapper::source::cache_endpoints() {
+        let url = format!("{}/artefact/{}", cache.trim_end_matches('/'), equiv_hash);
+        if let Ok(resp) = reqwest::blocking::get(&url) {
+            if resp.status().is_success() {
+                if let Ok(bytes) = resp.bytes() {
+                    return Some(bytes.to_vec());
+                }
+            }
+        }
+    }
+    None
+}
+
+fn push_remote_artefact(equiv_hash: &str, contents: &[u8]) {
+    for cache in bootstrapper::source::cache_endpoints() {
+        let url = format!("{}/artefact/{}", cache.trim_end_matches('/'), equiv_hash);
+        let _ = reqwest::blocking::Client::new()
+            .put(url)
+            .body(contents.to_vec())
+            .send();
+    }
+}
+
+fn push_remote_equiv(depd_hash: &str, equiv_hash: &str) {
+    for cache in bootstrapper::source::cache_endpoints() {
+        let url = format!("{}/equiv/{}", cache.trim_end_matches('/'), depd_hash);
+        let _ = reqwest::blocking::Client::new()
+            .put(url)
+            .body(equiv_hash.to_owned())
+            .send();
+    }
+}
+
 fn test_dep(dep: &(String, String)) -> bool {
     println!(" Testing for prebuilt {:?}...", dep);
     let equiv_hash = get_equiv_hash(&dep.0.clone(), &dep.1.clone(), "");
     println!("  Equivalent to {:?}", equiv_hash);
-    if let Some(hash) = equiv_hash {
-        std::fs::exists(dep_path(&hash)).unwrap()
-    } else {
-        false
+    if let Some(hash) = &equiv_hash {
+        if std::fs::exists(dep_path(hash)).unwrap() {
+            return true;
+        }
+    }
+    // Fall back to a remote substituter: it may already know this exact
+    // dependency tree even if our local equiv.sled has never seen it.
+    if let Some(depd_hash) = get_depd_hash(&dep.0, &dep.1, "") {
+        if let Some(hash) = remote_equiv_hash(&depd_hash) {
+            if let Some(artefact) = remote_artefact(&hash) {
+                let chunk_hashes = bootstrapper::chunkstore::store_chunks(&artefact);
+                bootstrapper::chunkstore::write_manifest(&dep_path(&hash), &chunk_hashes);
+                let db: sled::Db = sled::open("equiv.sled").unwrap();
+                db.insert(depd_hash, hash.as_str()).unwrap();
+                return true;
+            }
+        }
     }
+    false
 }
 
 fn load_dep(dep: &(String, String)) -> Vec<u8> {
     let equiv_hash = get_equiv_hash(&dep.0.clone(), &dep.1.clone(), "").unwrap();
-    std::fs::read(dep_path(&equiv_hash)).unwrap()
+    let chunk_hashes = bootstrapper::chunkstore::read_manifest(&dep_path(&equiv_hash));
+    bootstrapper::chunkstore::load_chunks(&chunk_hashes)
 }
 
+/// Stores `contents` as a [`bootstrapper::chunkstore`] manifest instead of a
+/// flat file under `dep_path`, so two artefacts that differ by only a few
+/// files (common across successive bootstrap stages) share chunks on disk
+/// instead of each paying for a full copy.
 fn store_dep(dep: &(String, String), contents: &[u8]) {
     let recipe_hash = get_depd_hash(&dep.0, &dep.1, "").unwrap();
     let equiv_hash = sha256::digest(contents);
-    let dep_path = dep_path(&equiv_hash);
-    std::fs::create_dir_all(dep_path.parent().unwrap()).unwrap();
-    std::fs::write(dep_path, contents).unwrap();
+    let chunk_hashes = bootstrapper::chunkstore::store_chunks(contents);
+    bootstrapper::chunkstore::write_manifest(&dep_path(&equiv_hash), &chunk_hashes);
     let db: sled::Db = sled::open("equiv.sled").unwrap();
-    db.insert(recipe_hash, equiv_hash.as_str()).unwrap();
+    db.insert(recipe_hash.clone(), equiv_hash.as_str()).unwrap();
+    push_remote_artefact(&equiv_hash, contents);
+    push_remote_equiv(&recipe_hash, &equiv_hash);
+}
+
+/// Prunes the build cache down to what's still reachable: every `equiv_hash`
+/// recorded in `equiv.sled` is live, along with its manifest's chunks;
+/// everything else under `build-cache/build` and `build-cache/chunks` is
+/// garbage left behind by a recipe that's since changed or been removed.
+fn gc() {
+    println!("Computing live set from equiv.sled...");
+    let db: sled::Db = sled::open("equiv.sled").unwrap();
+    let live_manifests: BTreeSet<String> = db
+        .iter()
+        .map(|entry| String::from_utf8(entry.unwrap().1.to_vec()).unwrap())
+        .collect();
+
+    let mut live_chunks = BTreeSet::new();
+    for equiv_hash in &live_manifests {
+        let manifest_path = dep_path(equiv_hash);
+        if manifest_path.exists() {
+            live_chunks.extend(bootstrapper::chunkstore::read_manifest(&manifest_path));
+        }
+    }
+    println!(
+        "{} live artefacts referencing {} live chunks",
+        live_manifests.len(),
+        live_chunks.len()
+    );
+
+    let mut removed_manifests = 0;
+    let build_dir = PathBuf::from("build-cache").join("build");
+    if build_dir.exists() {
+        for entry in WalkDir::new(&build_dir) {
+            let entry = entry.unwrap();
+            if !entry.metadata().unwrap().is_file() {
+                continue;
+            }
+            let hash = entry.file_name().to_str().unwrap().to_owned();
+            if !live_manifests.contains(&hash) {
+                std::fs::remove_file(entry.path()).unwrap();
+                removed_manifests += 1;
+            }
+        }
+    }
+
+    let mut removed_chunks = 0;
+    for hash in bootstrapper::chunkstore::all_chunk_hashes() {
+        if !live_chunks.contains(&hash) {
+            bootstrapper::chunkstore::remove_chunk(&hash);
+            removed_chunks += 1;
+        }
+    }
+
+    println!(
+        "Removed {} unreferenced manifest(s) and {} unreferenced chunk(s)",
+        removed_manifests, removed_chunks
+    );
+}
+
+/// A recipe's last-known `--verify` outcome, persisted alongside
+/// `equiv.sled` and keyed the same way (`get_depd_hash`), so a later run can
+/// skip re-verifying a package it's already double-built and compared.
+fn verified_status(dep: &(String, String)) -> Option<String> {
+    let depd_hash = get_depd_hash(&dep.0, &dep.1, "")?;
+    let db: sled::Db = sled::open("reproducible.sled").unwrap();
+    db.get(depd_hash)
+        .unwrap()
+        .map(|v| String::from_utf8(v.to_vec()).unwrap())
+}
+
+fn record_verified_status(dep: &(String, String), status: &str) {
+    if let Some(depd_hash) = get_depd_hash(&dep.0, &dep.1, "") {
+        let db: sled::Db = sled::open("reproducible.sled").unwrap();
+        db.insert(depd_hash, status).unwrap();
+    }
+}
+
+/// Runs one leg of a `--verify` double-build: pushes a `verify:`-tagged
+/// `WORK_QUEUE` entry (so it's handed to whichever worker is next free,
+/// ideally a different one than the other leg) and blocks for that worker's
+/// reported `(hash, archive)`, or the error it failed with.
+fn dispatch_verify_build(to_build: &(String, String)) -> Result<(String, Vec<u8>), String> {
+    let nonce = VERIFY_NONCE.fetch_add(1, Ordering::Relaxed).to_string();
+    let (tx, rx) = mpsc::channel();
+    RESULT_CHANNELS.lock().unwrap().insert(nonce.clone(), tx);
+    let key = format!("verify:{}:{}:{}", to_build.0, to_build.1, nonce);
+    while !WORK_QUEUE.push(key.clone()) {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    rx.recv().unwrap()
+}
+
+/// Per-file report of how two builds of the same recipe diverged: name,
+/// size on each side, and whether the content hash matched. Unlike a
+/// first-divergence check, this walks every entry so a maintainer can see
+/// the full blast radius (e.g. a build-path leaking into every object file)
+/// rather than just the first offending one.
+fn diff_archives(
+    a: &[u8],
+    b: &[u8],
+    compression: bootstrapper::archives::Compression,
+) -> Vec<String> {
+    fn entries(
+        data: &[u8],
+        compression: bootstrapper::archives::Compression,
+    ) -> BTreeMap<PathBuf, (u64, String)> {
+        let tar_data = bootstrapper::archives::decompress_tar(data, compression);
+        let mut archive = tar::Archive::new(std::io::Cursor::new(tar_data));
+        let mut out = BTreeMap::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().into_owned();
+            let size = entry.header().size().unwrap();
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content).unwrap();
+            out.insert(path, (size, sha256::digest(&content)));
+        }
+        out
+    }
+    let a = entries(a, compression);
+    let b = entries(b, compression);
+    let mut report = Vec::new();
+    for path in a.keys().chain(b.keys()).collect::<BTreeSet<_>>() {
+        match (a.get(path), b.get(path)) {
+            (Some((size_a, hash_a)), Some((size_b, hash_b))) => {
+                if size_a != size_b || hash_a != hash_b {
+                    report.push(format!(
+                        "{:?}: size {}/{}, hash {}/{}",
+                        path, size_a, size_b, hash_a, hash_b
+                    ));
+                }
+            }
+            (Some(_), None) => report.push(format!("{:?}: only present in first build", path)),
+            (None, Some(_)) => report.push(format!("{:?}: only present in second build", path)),
+            (None, None) => unreachable!(),
+        }
+    }
+    report
 }
 
 fn main() {
+    if std::env::args().nth(1).as_deref() == Some("gc") {
+        return gc();
+    }
+
     println!("Loading recipes...");
     let mut recipes = BTreeMap::new();
     for entry in glob::glob("recipes/*/**/*.yaml").unwrap() {
@@ -94,9 +373,8 @@ fn main() {
         let recipe: RecipeVersion =
             serde_yaml::from_reader(File::open(entry.clone()).unwrap()).unwrap();
 
-
         if recipe.licenses.is_none() {
-            println!("No license for {}:{}",name,version);
+            println!("No license for {}:{}", name, version);
         }
 
         match recipes.entry(name.to_owned()) {
@@ -125,44 +403,217 @@ fn main() {
         }
     }
 
-    let listener = TcpListener::bind("0.0.0.0:1234").unwrap();
-    println!("Waiting for worker...");
-    let (mut stream, _) = listener.accept().unwrap();
+    let deptree = Arc::new(Mutex::new(deptree));
+    let in_flight: Arc<Mutex<BTreeSet<(String, String)>>> = Arc::new(Mutex::new(BTreeSet::new()));
+    // Ready, confirmed-not-prebuilt nodes waiting for a worker whose
+    // advertised capabilities satisfy their recipe, claimed directly by
+    // handler threads via `claim_ready_build` rather than pushed onto the
+    // capability-blind `WORK_QUEUE`.
+    let buildable: Arc<Mutex<BTreeSet<(String, String)>>> = Arc::new(Mutex::new(BTreeSet::new()));
+    let gap_logged: Arc<Mutex<BTreeSet<(String, String)>>> = Arc::new(Mutex::new(BTreeSet::new()));
+    // Set once there's nothing left to dispatch, so idle worker threads know
+    // to tell their connection to stop asking for work instead of polling
+    // `WORK_QUEUE` forever.
+    let done = Arc::new(AtomicBool::new(false));
+    let verify_mode = std::env::args().any(|a| a == "--verify");
 
-    while let Some(to_build) = ready_to_build(&deptree).first() {
-        let to_build = (*to_build).clone();
-
-        println!("Considering {:?}", to_build);
-
-        if test_dep(&to_build) {
-            finish_dep(&mut deptree, &to_build);
-            continue;
+    let listener = TcpListener::bind("0.0.0.0:1234").unwrap();
+    println!("Waiting for workers...");
+
+    // Every accepted connection becomes its own long-lived handler thread.
+    // It first reads the worker's asserted `WorkerCapabilities` and registers
+    // them in `WORKERS`, then repeatedly either pops a `verify:`-tagged key
+    // off `WORK_QUEUE` (capability-agnostic - `--verify` double-builds don't
+    // care which worker runs which leg) or claims a capability-compatible
+    // node off `buildable` via `claim_ready_build`, running whichever it gets
+    // to completion over that connection's `TcpStream` before asking for
+    // another. Accepting more connections is how the coordinator grows the
+    // worker pool.
+    std::thread::spawn({
+        let deptree = deptree.clone();
+        let in_flight = in_flight.clone();
+        let buildable = buildable.clone();
+        let done = done.clone();
+        move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let deptree = deptree.clone();
+                let in_flight = in_flight.clone();
+                let buildable = buildable.clone();
+                let done = done.clone();
+                std::thread::spawn(move || {
+                    let caps = read_capabilities(&mut stream);
+                    let addr = stream.peer_addr().unwrap();
+                    println!(" Worker {} connected: {:?}", addr, caps);
+                    WORKERS.lock().unwrap().insert(addr, caps.clone());
+
+                    loop {
+                        if let Some(key) = WORK_QUEUE.pop() {
+                            let rest = key.strip_prefix("verify:").unwrap();
+                            let mut parts = rest.splitn(3, ':');
+                            let name = parts.next().unwrap().to_owned();
+                            let version = parts.next().unwrap().to_owned();
+                            let nonce = parts.next().unwrap().to_owned();
+                            let to_build = (name, version);
+
+                            println!(" Dispatching (verify) {:?}", to_build);
+                            let result = build_recipe(&mut stream, to_build).map(|archive_buf| {
+                                let hash = sha256::digest(&archive_buf);
+                                (hash, archive_buf)
+                            });
+                            let tx = RESULT_CHANNELS.lock().unwrap().remove(&nonce).unwrap();
+                            let _ = tx.send(result);
+                            continue;
+                        }
+
+                        if let Some(to_build) = claim_ready_build(&buildable, &in_flight, &caps) {
+                            println!(" Dispatching {:?}", to_build);
+                            match build_recipe(&mut stream, to_build.clone()) {
+                                Ok(archive_buf) => {
+                                    store_dep(&to_build, &archive_buf);
+                                    finish_dep(&mut deptree.lock().unwrap(), &to_build);
+                                }
+                                Err(msg) => {
+                                    // Leave it out of `deptree`'s finished set so the
+                                    // main loop's `ready_to_build` offers it again -
+                                    // removing it from `in_flight` below is what makes
+                                    // that retry possible.
+                                    println!(" Build failed {:?}: {}", to_build, msg);
+                                }
+                            }
+                            in_flight.lock().unwrap().remove(&to_build);
+                            continue;
+                        }
+
+                        if done.load(Ordering::Relaxed) {
+                            assert_eq!(stream.read_u8().unwrap(), WorkerStatus::ReadyForWork as u8);
+                            stream.write_u8(1).unwrap();
+                            break;
+                        }
+                        std::thread::sleep(Duration::from_millis(20));
+                    }
+
+                    WORKERS.lock().unwrap().remove(&addr);
+                });
+            }
         }
+    });
 
-        println!(" Dispatching {:?}", to_build);
+    loop {
+        let ready: Vec<(String, String)> = {
+            let deptree_guard = deptree.lock().unwrap();
+            let in_flight_guard = in_flight.lock().unwrap();
+            if deptree_guard.is_empty() && in_flight_guard.is_empty() {
+                break;
+            }
+            ready_to_build(&deptree_guard)
+                .into_iter()
+                .filter(|k| !in_flight_guard.contains(*k))
+                .cloned()
+                .collect()
+        };
+
+        for to_build in ready {
+            if buildable.lock().unwrap().contains(&to_build) {
+                // Already offered to the pool; still waiting on a worker
+                // whose capabilities satisfy this recipe.
+                report_capability_gap(&to_build, &gap_logged);
+                continue;
+            }
 
-        let archive_buf = build_recipe(&mut stream, to_build.clone());
+            println!("Considering {:?}", to_build);
 
-        store_dep(&to_build, &archive_buf);
+            if test_dep(&to_build) {
+                finish_dep(&mut deptree.lock().unwrap(), &to_build);
+                continue;
+            }
 
-        finish_dep(&mut deptree, &to_build);
-    }
+            if verify_mode && verified_status(&to_build).is_none() {
+                in_flight.lock().unwrap().insert(to_build.clone());
+                let deptree = deptree.clone();
+                let in_flight = in_flight.clone();
+                std::thread::spawn(move || {
+                    println!(" Verifying reproducibility of {:?}", to_build);
+                    let leg_a = dispatch_verify_build(&to_build);
+                    let leg_b = dispatch_verify_build(&to_build);
+                    match (leg_a, leg_b) {
+                        (Ok((hash_a, archive_a)), Ok((hash_b, archive_b))) => {
+                            if hash_a == hash_b {
+                                println!("  reproducible: {}", hash_a);
+                                record_verified_status(&to_build, "reproducible");
+                            } else {
+                                println!("  NOT reproducible: {} vs {}", hash_a, hash_b);
+                                record_verified_status(&to_build, "nonreproducible");
+                                let recipe = NamedRecipeVersion::load_by_target_version(
+                                    &to_build.0,
+                                    &to_build.1,
+                                );
+                                for line in
+                                    diff_archives(&archive_a, &archive_b, recipe.compression)
+                                {
+                                    println!("  {}", line);
+                                }
+                            }
+                            store_dep(&to_build, &archive_a);
+                            finish_dep(&mut deptree.lock().unwrap(), &to_build);
+                        }
+                        (leg_a, leg_b) => {
+                            // Leave it unfinished so the scheduler offers it again -
+                            // removing it from `in_flight` below is what allows that.
+                            for leg in [leg_a, leg_b] {
+                                if let Err(msg) = leg {
+                                    println!(" Verify build failed {:?}: {}", to_build, msg);
+                                }
+                            }
+                        }
+                    }
+                    in_flight.lock().unwrap().remove(&to_build);
+                });
+                continue;
+            }
 
-    assert_eq!(stream.read_u8().unwrap(), WorkerStatus::ReadyForWork as u8);
+            buildable.lock().unwrap().insert(to_build.clone());
+            report_capability_gap(&to_build, &gap_logged);
+        }
 
-    stream.write_u8(1).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    done.store(true, Ordering::Relaxed);
 
+    let deptree = deptree.lock().unwrap();
     if !deptree.is_empty() {
         println!("Remaining packages:");
-        for ((name,ver),deps) in deptree.iter() {
+        for ((name, ver), deps) in deptree.iter() {
             //if deps.len() == 1 {
-                println!("{}:{} -> {:?}",name,ver,deps);
+            println!("{}:{} -> {:?}", name, ver, deps);
             //}
         }
     }
 }
 
-fn build_recipe(stream: &mut TcpStream, to_build: (String, String)) -> Vec<u8> {
+/// Applies `dir`'s `env` file (if it has one) as the next layer onto `env`,
+/// via `apply_env_layer`. A directory with no `env` file simply contributes
+/// nothing - walking up past the recipes root or into a recipe that hasn't
+/// bothered with one is not an error.
+fn apply_env_layer_if_present(
+    dir: &Path,
+    env: &mut BTreeMap<String, String>,
+    visited: &mut BTreeSet<PathBuf>,
+) {
+    let env_path = dir.join("env");
+    if env_path.exists() {
+        bootstrapper::apply_env_layer(&env_path, env, visited).unwrap();
+    }
+}
+
+/// Drives one worker through a full build over `stream`, returning the
+/// packaged archive on success or the worker's reported error message if the
+/// build failed (e.g. a source wouldn't extract) - see
+/// `bootstrapper::network::write_build_failed`.
+fn build_recipe(stream: &mut TcpStream, to_build: (String, String)) -> Result<Vec<u8>, String> {
     assert_eq!(stream.read_u8().unwrap(), WorkerStatus::ReadyForWork as u8);
 
     stream.write_u8(0).unwrap();
@@ -178,12 +629,9 @@ fn build_recipe(stream: &mut TcpStream, to_build: (String, String)) -> Vec<u8> {
     if let Some(sources) = recipe.source {
         for (name, _) in sources {
             let source_contents = SOURCES.get(&name).unwrap();
-            let spath = source_path(&source_contents.sha);
-            let source_data = if spath.exists() {
-                std::fs::read(spath).unwrap()
-            } else {
-                fetch_source(source_contents)
-            };
+            let source_data = source::resolved_source_path(source_contents)
+                .and_then(|p| std::fs::read(p).ok())
+                .unwrap_or_else(|| fetch_source(source_contents));
             write_source(stream, &name, source_contents, &source_data);
         }
     }
@@ -215,27 +663,44 @@ fn build_recipe(stream: &mut TcpStream, to_build: (String, String)) -> Vec<u8> {
     }
     finish_overlays(stream);
 
+    // Layer `env` files found walking from the recipes root down to this
+    // recipe's own directory (outer layers overridden by inner ones), via
+    // `apply_env_layer`'s `%include`/`%unset` support - so shared fragments
+    // live once near the root instead of being copy-pasted into every
+    // recipe's `env` file.
     let mut dir_envs = BTreeMap::new();
-    if let Ok(v) = std::fs::read(
-        PathBuf::from(format!("recipes/{}.yaml", to_build.0))
-            .parent()
-            .unwrap()
-            .join("env"),
-    ) {
-        for line in String::from_utf8(v).unwrap().split('\n') {
-            let (k, v) = line.split_once('=').unwrap();
-            dir_envs.insert(k.to_owned(), env_substitute(v.trim_matches('"'), &dir_envs));
-        }
-    };
+    let mut visited = BTreeSet::new();
+    let mut layer_dir = PathBuf::from("recipes");
+    apply_env_layer_if_present(&layer_dir, &mut dir_envs, &mut visited);
+    for component in to_build.0.split('/') {
+        layer_dir.push(component);
+        apply_env_layer_if_present(&layer_dir, &mut dir_envs, &mut visited);
+    }
 
     write_envs(stream, dir_envs);
 
-    assert_eq!(stream.read_u8().unwrap(), WorkerStatus::BuildComplete as u8);
+    loop {
+        let tag = stream.read_u8().unwrap();
+        if tag == WorkerStatus::BuildComplete as u8 {
+            break;
+        }
+        if tag == WorkerStatus::BuildFailed as u8 {
+            return Err(read_build_failed(stream));
+        }
+        assert_eq!(tag, WorkerStatus::StatusUpdate as u8);
+        match read_status_update(stream) {
+            StatusUpdate::CommandRun(cmd) => println!("  $ {:?}", cmd),
+            StatusUpdate::CommandOut(line) => println!("  {}", line),
+            StatusUpdate::CommandError(line) => println!("  ! {}", line),
+            StatusUpdate::CommandDone(code) => println!("  (exit {})", code),
+            StatusUpdate::Done => println!("  done"),
+        }
+    }
     let mut hash = vec![0u8; 64];
     stream.read_exact(hash.as_mut_slice()).unwrap();
     let archive_len = stream.read_u64::<byteorder::BigEndian>().unwrap();
     let mut archive_buf = vec![0u8; archive_len.try_into().unwrap()];
     stream.read_exact(archive_buf.as_mut_slice()).unwrap();
 
-    archive_buf
+    Ok(archive_buf)
 }
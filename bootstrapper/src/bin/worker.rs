@@ -1,71 +1,531 @@
 use std::{
     collections::BTreeMap,
     fs::{create_dir, create_dir_all, read_dir},
-    io::Cursor,
+    io::{BufRead, BufReader, Cursor, Read},
     net::TcpStream,
-    path::PathBuf,
-    process::{Child, Command},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::{Arc, Condvar, Mutex},
 };
 
 use base64::engine::general_purpose::URL_SAFE;
 use base64::Engine;
 use bootstrapper::{
-    archives::{source_extract_filter, Archiver},
-    env_substitute,
-    network::{read_deps, read_envs, read_overlays, read_recipe, read_sources, write_archive},
+    archives::{source_extract_filter, Archiver, ExtractOptions},
+    network::{write_archive, write_status_update, LocalSource, RecipeSource, StatusUpdate},
     recipe::{NamedRecipeVersion, RecipeBuildStep, SourceContents},
+    recipe_lang::{self, Step, StepAttrs},
     sanitize_path, WorkerStatus,
 };
 use byteorder::{ReadBytesExt, WriteBytesExt};
-use bzip2::read::BzDecoder;
 use nix::{
     mount::{mount, umount, MsFlags},
-    sys::stat::{makedev, mknod, Mode, SFlag},
+    sys::{
+        signal::{kill, Signal},
+        stat::{makedev, mknod, Mode, SFlag},
+    },
+    unistd::Pid,
 };
+use petgraph::graph::{DiGraph, NodeIndex};
 use regex::Regex;
 use tempfile::TempDir;
 
-#[derive(Debug)]
-enum StatusUpdate {
-    CommandRun(Vec<String>),
-    CommandOut(String),
-    CommandError(String),
-    CommandDone(i32),
-    Done,
+/// `-j N` from argv, i.e. the maximum number of build steps the `Scheduler`
+/// will let run concurrently. Defaults to 4 when not given.
+fn parallelism_from_args() -> usize {
+    let args: Vec<String> = std::env::args().collect();
+    args.windows(2)
+        .find_map(|w| (w[0] == "-j").then(|| w[1].parse().unwrap()))
+        .unwrap_or(4)
+}
+
+/// The value following `flag` in argv, e.g. `arg_value("--connect")` for
+/// `worker --connect 10.0.0.1:1234`.
+fn arg_value(flag: &str) -> Option<String> {
+    let args: Vec<String> = std::env::args().collect();
+    args.windows(2)
+        .find_map(|w| (w[0] == flag).then(|| w[1].clone()))
+}
+
+/// Every value following a (possibly repeated) `flag` in argv, e.g.
+/// `arg_values("--tool")` for `worker --tool gcc=12.2 --tool make=4.3`.
+fn arg_values(flag: &str) -> Vec<String> {
+    let args: Vec<String> = std::env::args().collect();
+    args.windows(2)
+        .filter_map(|w| (w[0] == flag).then(|| w[1].clone()))
+        .collect()
+}
+
+/// What this worker asserts about itself on connect: the target
+/// architecture and kernel release, auto-detected via `std::env::consts`
+/// and `uname`, each overridable with `--arch`/`--kernel` for
+/// cross-building or testing; and the host tools it has available, given as
+/// repeated `--tool NAME=VERSION` pairs (there's no way to auto-detect an
+/// arbitrary recipe's idea of "available tools", so this is asserted
+/// explicitly rather than guessed).
+fn detect_capabilities() -> bootstrapper::network::WorkerCapabilities {
+    let uname = nix::sys::utsname::uname().unwrap();
+    let tools = arg_values("--tool")
+        .into_iter()
+        .filter_map(|kv| {
+            kv.split_once('=')
+                .map(|(name, version)| (name.to_owned(), version.to_owned()))
+        })
+        .collect();
+    bootstrapper::network::WorkerCapabilities {
+        arch: arg_value("--arch").unwrap_or_else(|| std::env::consts::ARCH.to_owned()),
+        kernel: arg_value("--kernel")
+            .unwrap_or_else(|| uname.release().to_string_lossy().into_owned()),
+        tools,
+    }
 }
 
 fn main() {
-    let mut stream = TcpStream::connect("127.0.0.1:1234").unwrap();
+    let parallelism = parallelism_from_args();
+
+    if std::env::args().any(|a| a == "--local") {
+        run_local(parallelism);
+    } else {
+        run_network(parallelism);
+    }
+}
+
+/// The normal mode: fetch recipes from a TCP coordinator in a loop, one
+/// after another, until it reports there's no more work. `--connect
+/// HOST:PORT` overrides the coordinator address, which used to be
+/// hardcoded to `127.0.0.1:1234`. A recipe whose build fails (e.g. a source
+/// won't extract) is reported to the coordinator via `write_build_failed`
+/// rather than panicking the worker - the loop just moves on to the next
+/// recipe.
+fn run_network(parallelism: usize) {
+    let endpoint = arg_value("--connect").unwrap_or_else(|| "127.0.0.1:1234".to_owned());
+    let mut stream = TcpStream::connect(endpoint).unwrap();
+    bootstrapper::network::write_capabilities(&mut stream, &detect_capabilities());
 
     loop {
         stream.write_u8(WorkerStatus::ReadyForWork as u8).unwrap();
 
-        if stream.read_u8().unwrap() == 1 { break; }
+        if stream.read_u8().unwrap() == 1 {
+            break;
+        }
 
-        let recipe = read_recipe(&mut stream);
+        let recipe = stream.read_recipe();
 
-        let source_data = read_sources(&mut stream);
+        let source_data = stream.read_sources();
 
-        let dep_data = read_deps(&mut stream);
+        let dep_data = stream.read_deps();
 
-        let overlay_data = read_overlays(&mut stream);
+        let overlay_data = stream.read_overlays();
 
-        let env_data = read_envs(&mut stream);
+        let env_data = stream.read_envs();
 
         let (pq_s, pq_r) = std::sync::mpsc::channel();
 
-        let jh = std::thread::spawn(|| {
-            build(recipe, source_data, dep_data, overlay_data, env_data, pq_s)
+        let jh = std::thread::spawn(move || {
+            build(
+                recipe,
+                source_data,
+                dep_data,
+                overlay_data,
+                env_data,
+                pq_s,
+                parallelism,
+            )
         });
 
         while let Ok(msg) = pq_r.recv() {
-            println!("{:?}", msg)
+            println!("{:?}", msg);
+            write_status_update(&mut stream, &msg);
+        }
+
+        match jh.join().unwrap() {
+            Ok((hash, archive)) => {
+                write_archive(&mut stream, &hash, &archive);
+                println!("{}", hash)
+            }
+            Err(e) => {
+                eprintln!("build failed: {}", e);
+                bootstrapper::network::write_build_failed(&mut stream, &e.to_string());
+            }
+        }
+    }
+}
+
+/// `--local`: build exactly one recipe from local paths instead of a
+/// coordinator, and write the resulting archive straight to `--out`. Lets a
+/// developer reproduce or debug a single recipe's build without standing up
+/// `server`. With `--verify-reproducible`, builds it twice instead and
+/// compares the two results rather than writing an archive.
+fn run_local(parallelism: usize) {
+    let mut source = LocalSource {
+        recipe_path: arg_value("--recipe").map(PathBuf::from),
+        sources_dir: arg_value("--sources").map(PathBuf::from),
+        deps_dir: arg_value("--deps").map(PathBuf::from),
+        overlays_dir: arg_value("--overlays").map(PathBuf::from),
+        envs_path: arg_value("--envs").map(PathBuf::from),
+    };
+
+    let recipe = source.read_recipe();
+    let source_data = source.read_sources();
+    let dep_data = source.read_deps();
+    let overlay_data = source.read_overlays();
+    let env_data = source.read_envs();
+
+    if std::env::args().any(|a| a == "--verify-reproducible") {
+        verify_reproducible(
+            recipe,
+            source_data,
+            dep_data,
+            overlay_data,
+            env_data,
+            parallelism,
+        );
+        return;
+    }
+
+    let out_path = PathBuf::from(arg_value("--out").expect("--local requires --out <path>"));
+    match run_build(
+        recipe,
+        source_data,
+        dep_data,
+        overlay_data,
+        env_data,
+        parallelism,
+    ) {
+        Ok((hash, archive)) => {
+            std::fs::write(&out_path, &archive).unwrap();
+            println!("{}", hash)
+        }
+        Err(e) => {
+            eprintln!("build failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Run one full build of `recipe` against pre-fetched inputs, printing each
+/// `StatusUpdate` as it arrives. The thread+channel dance `run_network`'s
+/// loop also does, factored out so `--verify-reproducible` can run it twice.
+fn run_build(
+    recipe: NamedRecipeVersion,
+    source_data: BTreeMap<String, (SourceContents, Vec<u8>)>,
+    dep_data: BTreeMap<String, Vec<u8>>,
+    overlay_data: BTreeMap<PathBuf, Vec<u8>>,
+    env_data: BTreeMap<String, String>,
+    parallelism: usize,
+) -> Result<(String, Vec<u8>), BuildError> {
+    let (pq_s, pq_r) = std::sync::mpsc::channel();
+
+    let jh = std::thread::spawn(move || {
+        build(
+            recipe,
+            source_data,
+            dep_data,
+            overlay_data,
+            env_data,
+            pq_s,
+            parallelism,
+        )
+    });
+
+    while let Ok(msg) = pq_r.recv() {
+        println!("{:?}", msg);
+    }
+
+    jh.join().unwrap()
+}
+
+/// `--verify-reproducible`: build `recipe` twice, each in its own
+/// `tempfile::tempdir_in("ramdir/")` work dir (see `build`), and compare
+/// the two resulting hashes. A mismatch doesn't just fail - it decompresses
+/// both packages and reports the first diverging tar entry, so a recipe
+/// author gets an actionable pointer instead of two unequal hex strings.
+fn verify_reproducible(
+    recipe: NamedRecipeVersion,
+    source_data: BTreeMap<String, (SourceContents, Vec<u8>)>,
+    dep_data: BTreeMap<String, Vec<u8>>,
+    overlay_data: BTreeMap<PathBuf, Vec<u8>>,
+    env_data: BTreeMap<String, String>,
+    parallelism: usize,
+) {
+    let compression = recipe.compression;
+
+    let (hash_a, archive_a) = match run_build(
+        recipe.clone(),
+        source_data.clone(),
+        dep_data.clone(),
+        overlay_data.clone(),
+        env_data.clone(),
+        parallelism,
+    ) {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("build failed: {}", e);
+            return;
+        }
+    };
+    let (hash_b, archive_b) = match run_build(
+        recipe,
+        source_data,
+        dep_data,
+        overlay_data,
+        env_data,
+        parallelism,
+    ) {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("build failed: {}", e);
+            return;
+        }
+    };
+
+    if hash_a == hash_b {
+        println!("reproducible: {}", hash_a);
+        return;
+    }
+
+    println!("NOT reproducible: {} vs {}", hash_a, hash_b);
+    let tar_a = bootstrapper::archives::decompress_tar(&archive_a, compression);
+    let tar_b = bootstrapper::archives::decompress_tar(&archive_b, compression);
+    match diff_tars(&tar_a, &tar_b) {
+        Some(divergence) => println!("first divergence: {}", divergence),
+        None => println!("hashes differ but every tar entry matched - check trailing padding"),
+    }
+}
+
+/// Parse both package tars and return a description of the first entry
+/// that diverges in path, mode, size or content between them - mirroring
+/// the node-by-node comparison used to validate dry-run equivalence - or
+/// `None` if every entry matches byte-for-byte.
+fn diff_tars(a: &[u8], b: &[u8]) -> Option<String> {
+    fn entries(data: &[u8]) -> Vec<(PathBuf, u32, u64, Vec<u8>)> {
+        let mut out = Vec::new();
+        let mut archive = tar::Archive::new(Cursor::new(data));
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().into_owned();
+            let mode = entry.header().mode().unwrap();
+            let size = entry.header().size().unwrap();
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content).unwrap();
+            out.push((path, mode, size, content));
+        }
+        out.sort_by(|x, y| x.0.cmp(&y.0));
+        out
+    }
+
+    let a = entries(a);
+    let b = entries(b);
+
+    for i in 0..a.len().max(b.len()) {
+        match (a.get(i), b.get(i)) {
+            (Some((pa, ma, sa, ca)), Some((pb, mb, sb, cb))) => {
+                if pa != pb || ma != mb || sa != sb || ca != cb {
+                    return Some(format!(
+                        "{:?} vs {:?}: mode {:#o}/{:#o}, size {}/{}, content {}",
+                        pa,
+                        pb,
+                        ma,
+                        mb,
+                        sa,
+                        sb,
+                        if ca == cb { "identical" } else { "differs" }
+                    ));
+                }
+            }
+            (Some((pa, ..)), None) => return Some(format!("{:?} only present in first build", pa)),
+            (None, Some((pb, ..))) => {
+                return Some(format!("{:?} only present in second build", pb))
+            }
+            (None, None) => unreachable!(),
+        }
+    }
+    None
+}
+
+/// One node in a `Scheduler`'s dependency DAG: lets any thread block until
+/// this step has finished, and learn whether it (and everything it
+/// transitively depended on) succeeded.
+struct StepNode {
+    state: Mutex<Option<bool>>,
+    cv: Condvar,
+}
+
+impl StepNode {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(None),
+            cv: Condvar::new(),
+        }
+    }
+
+    fn wait_success(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        while state.is_none() {
+            state = self.cv.wait(state).unwrap();
+        }
+        state.unwrap()
+    }
+
+    fn finish(&self, success: bool) {
+        *self.state.lock().unwrap() = Some(success);
+        self.cv.notify_all();
+    }
+}
+
+/// A counting semaphore bounding how many steps the `Scheduler` lets run at
+/// once (the `-j N` parallelism cap).
+struct Semaphore {
+    count: Mutex<usize>,
+    cv: Condvar,
+}
+
+impl Semaphore {
+    fn new(n: usize) -> Self {
+        Self {
+            count: Mutex::new(n.max(1)),
+            cv: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self) {
+        let mut count = self.count.lock().unwrap();
+        while *count == 0 {
+            count = self.cv.wait(count).unwrap();
+        }
+        *count -= 1;
+    }
+
+    fn release(&self) {
+        *self.count.lock().unwrap() += 1;
+        self.cv.notify_one();
+    }
+}
+
+/// A bounded-parallelism scheduler for build steps, built around an
+/// explicit dependency DAG (a `petgraph` graph, per step) instead of the
+/// old all-or-nothing "background until the next serial step" barrier.
+///
+/// Every step is `submit`-ted as soon as `do_step` reaches it and runs on
+/// its own thread, blocking only until the steps it actually depends on
+/// have finished: `serial` steps (and `RecipeBuildStep::Simple`, which is
+/// always serial) depend on every step submitted so far, for backward
+/// compatibility; everything else depends only on the step `id`s it names
+/// in `deps`. At most `parallelism` steps run concurrently.
+///
+/// Because a step can only name an `id` that's already been submitted,
+/// `deps` edges only ever point from an existing node to the new one - a
+/// true cycle can't arise from this graph no matter how recipes are
+/// written. A `deps` entry naming an id that hasn't been submitted yet (the
+/// only way a recipe could attempt something cycle-shaped) is instead
+/// caught directly in `submit`, which reports the offending id.
+struct Scheduler {
+    graph: DiGraph<Arc<StepNode>, ()>,
+    ids: BTreeMap<String, NodeIndex>,
+    all_so_far: Vec<NodeIndex>,
+    semaphore: Arc<Semaphore>,
+    threads: Vec<std::thread::JoinHandle<()>>,
+}
+
+impl Scheduler {
+    fn new(parallelism: usize) -> Self {
+        Self {
+            graph: DiGraph::new(),
+            ids: BTreeMap::new(),
+            all_so_far: Vec::new(),
+            semaphore: Arc::new(Semaphore::new(parallelism)),
+            threads: Vec::new(),
+        }
+    }
+
+    /// Wait for every step submitted so far to finish. A failed step (with
+    /// no `ignore_errors`) aborts the process from its own thread, so by
+    /// the time this returns the rest are known-good.
+    fn drain(&mut self) {
+        for t in self.threads.drain(..) {
+            t.join().unwrap();
+        }
+    }
+
+    fn submit(
+        &mut self,
+        cmd: Vec<String>,
+        id: Option<String>,
+        deps: Vec<String>,
+        must_be_serial: bool,
+        attrs: StepAttrs,
+        work_dir: PathBuf,
+        cur_dir: PathBuf,
+        env_data: BTreeMap<String, String>,
+        status_updates: std::sync::mpsc::Sender<StatusUpdate>,
+    ) {
+        let node = self.graph.add_node(Arc::new(StepNode::new()));
+
+        let mut preds: Vec<NodeIndex> = deps
+            .iter()
+            .map(|id| {
+                *self
+                    .ids
+                    .get(id)
+                    .unwrap_or_else(|| panic!("step depends on unknown id {:?}", id))
+            })
+            .collect();
+        if must_be_serial {
+            preds.extend(self.all_so_far.iter().copied());
+        }
+        for pred in &preds {
+            self.graph.add_edge(*pred, node, ());
         }
+        if let Some(id) = id {
+            self.ids.insert(id, node);
+        }
+        self.all_so_far.push(node);
 
-        let (hash, archive) = jh.join().unwrap();
+        let pred_nodes: Vec<Arc<StepNode>> =
+            preds.iter().map(|pred| self.graph[*pred].clone()).collect();
+        let this_node = self.graph[node].clone();
+        let semaphore = self.semaphore.clone();
 
-        write_archive(&mut stream, &hash, &archive);
-        println!("{}", hash)
+        self.threads.push(std::thread::spawn(move || {
+            let preds_ok = pred_nodes.iter().all(|pred| pred.wait_success());
+            if !preds_ok {
+                this_node.finish(attrs.ignore_errors);
+                if !attrs.ignore_errors {
+                    std::process::abort();
+                }
+                return;
+            }
+
+            semaphore.acquire();
+            let mut attempts_left = attrs.retry + 1;
+            let success = loop {
+                attempts_left -= 1;
+                status_updates
+                    .send(StatusUpdate::CommandRun(cmd.clone()))
+                    .unwrap();
+                let mut child = spawn_chroothelper(
+                    &cmd,
+                    &attrs,
+                    &work_dir,
+                    &cur_dir,
+                    &env_data,
+                    &status_updates,
+                );
+                let status = child.wait().unwrap();
+                status_updates
+                    .send(StatusUpdate::CommandDone(status.code().unwrap_or(-1)))
+                    .unwrap();
+                if status.success() || attempts_left == 0 {
+                    break status.success();
+                }
+            };
+            semaphore.release();
+
+            this_node.finish(success || attrs.ignore_errors);
+            if !success && !attrs.ignore_errors {
+                std::process::abort();
+            }
+        }));
     }
 }
 
@@ -75,69 +535,187 @@ fn do_step(
     cur_dir: &mut PathBuf,
     work_dir: &TempDir,
     status_updates: &std::sync::mpsc::Sender<StatusUpdate>,
-    join_handles: &mut Vec<Child>,
+    scheduler: &mut Scheduler,
 ) {
     let i;
     let must_be_serial;
     let bash_noescape;
+    let id;
+    let deps;
     match step {
         RecipeBuildStep::Simple(cmd) => {
             i = cmd;
             must_be_serial = true;
             bash_noescape = false;
+            id = None;
+            deps = Vec::new();
         }
-        RecipeBuildStep::Complex { cmd, serial, bash } => {
+        RecipeBuildStep::Complex {
+            cmd,
+            serial,
+            bash,
+            id: step_id,
+            deps: step_deps,
+        } => {
             i = cmd;
             must_be_serial = *serial;
             bash_noescape = *bash;
+            id = step_id.clone();
+            deps = step_deps.clone();
         }
     }
-    if i.split(' ').next().unwrap().contains('=') && !bash_noescape {
-        let (k, v) = i.split_once('=').unwrap();
-        env_data.insert(k.to_owned(), env_substitute(v, &env_data));
-    } else {
-        let cmd = if bash_noescape {
-            ["bash", "-c", i].map(|x| x.to_owned()).to_vec()
-        } else {
-            let env_substitute = env_substitute(&i, &env_data);
-            let i = env_substitute;
-            let cmd = shlex::split(&i).unwrap_or_else(|| panic!("Failed at line: {}", i));
-            if cmd[0] == "cd" {
-                *cur_dir = sanitize_path(&cur_dir.join(cmd[1].clone()));
-                return;
-            }
+    let (attrs, i) = recipe_lang::parse_attrs(i)
+        .unwrap_or_else(|e| panic!("Failed to parse step {:?}: {}", i, e));
+
+    if bash_noescape {
+        let cmd = ["bash", "-c", i].map(|x| x.to_owned()).to_vec();
+        run_command(
+            cmd,
+            id,
+            deps,
+            must_be_serial,
+            attrs,
+            work_dir,
+            cur_dir,
+            env_data,
+            status_updates,
+            scheduler,
+        );
+        return;
+    }
+
+    let step = recipe_lang::parse_step(i)
+        .unwrap_or_else(|e| panic!("Failed to parse step {:?}: {}", i, e));
+    match step {
+        Step::Assignment { name, value } => {
+            let value = recipe_lang::eval_expr(&value, env_data).unwrap();
+            env_data.insert(name, value);
+        }
+        Step::Cd { path } => {
+            let path = recipe_lang::eval_expr(&path, env_data).unwrap();
+            *cur_dir = sanitize_path(&cur_dir.join(path));
+        }
+        Step::Command { args } => {
+            let cmd = recipe_lang::eval_args(&args, env_data).unwrap();
             if cmd[0] == "alias" {
                 todo!();
             }
-            cmd
-        };
-        let builddir = URL_SAFE.encode(work_dir.path().as_os_str().as_encoded_bytes());
-        let chdir = URL_SAFE.encode(cur_dir.as_os_str().as_encoded_bytes());
-        let buildstep = URL_SAFE.encode(serde_yaml::to_string(&cmd).unwrap());
-        let env = URL_SAFE.encode(serde_yaml::to_string(&env_data).unwrap());
-        if must_be_serial {
-            join_handles
-                .drain(..)
-                .for_each(|mut res: std::process::Child| assert!(res.wait().unwrap().success()));
-        }
-        status_updates.send(StatusUpdate::CommandRun(cmd)).unwrap();
-        let mut res = Command::new("bootstrapper/target/debug/chroothelper")
-            .arg(builddir)
-            .arg(chdir)
-            .arg(buildstep)
-            .arg(env)
-            .spawn()
-            .unwrap();
-        if must_be_serial {
-            if !(res.wait().unwrap().success()) {
-                std::process::abort()
-            }
-        } else {
-            join_handles.push(res);
+            run_command(
+                cmd,
+                id,
+                deps,
+                must_be_serial,
+                attrs,
+                work_dir,
+                cur_dir,
+                env_data,
+                status_updates,
+                scheduler,
+            );
         }
     }
 }
 
+/// Spawn `cmd` through chroothelper with stdout/stderr piped. Forwards each
+/// captured line as a `CommandOut`/`CommandError` update on its own thread,
+/// and - if `attrs.timeout_secs` is set - kills the process from a third
+/// watcher thread once the timeout elapses.
+fn spawn_chroothelper(
+    cmd: &[String],
+    attrs: &StepAttrs,
+    work_dir: &Path,
+    cur_dir: &Path,
+    env_data: &BTreeMap<String, String>,
+    status_updates: &std::sync::mpsc::Sender<StatusUpdate>,
+) -> Child {
+    let builddir = URL_SAFE.encode(work_dir.as_os_str().as_encoded_bytes());
+    let chdir = URL_SAFE.encode(cur_dir.as_os_str().as_encoded_bytes());
+    let buildstep = URL_SAFE.encode(serde_yaml::to_string(&cmd).unwrap());
+    let env = URL_SAFE.encode(serde_yaml::to_string(&env_data).unwrap());
+    let step_attrs = URL_SAFE.encode(serde_yaml::to_string(&attrs).unwrap());
+
+    let mut child = Command::new("bootstrapper/target/debug/chroothelper")
+        .arg(builddir)
+        .arg(chdir)
+        .arg(buildstep)
+        .arg(env)
+        .arg(step_attrs)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let stdout = child.stdout.take().unwrap();
+    let out_updates = status_updates.clone();
+    std::thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            out_updates.send(StatusUpdate::CommandOut(line)).unwrap();
+        }
+    });
+    let stderr = child.stderr.take().unwrap();
+    let err_updates = status_updates.clone();
+    std::thread::spawn(move || {
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            err_updates.send(StatusUpdate::CommandError(line)).unwrap();
+        }
+    });
+
+    if let Some(secs) = attrs.timeout_secs {
+        let pid = Pid::from_raw(child.id() as i32);
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_secs(secs));
+            let _ = kill(pid, Signal::SIGKILL);
+        });
+    }
+    child
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_command(
+    cmd: Vec<String>,
+    id: Option<String>,
+    deps: Vec<String>,
+    must_be_serial: bool,
+    attrs: StepAttrs,
+    work_dir: &TempDir,
+    cur_dir: &PathBuf,
+    env_data: &BTreeMap<String, String>,
+    status_updates: &std::sync::mpsc::Sender<StatusUpdate>,
+    scheduler: &mut Scheduler,
+) {
+    scheduler.submit(
+        cmd,
+        id,
+        deps,
+        must_be_serial,
+        attrs,
+        work_dir.path().to_owned(),
+        cur_dir.clone(),
+        env_data.clone(),
+        status_updates.clone(),
+    );
+}
+
+/// Why a [`build`] aborted partway through, naming which input it choked on
+/// so a coordinator can report which source or dep failed instead of the
+/// whole build just disappearing.
+#[derive(Debug, thiserror::Error)]
+pub enum BuildError {
+    #[error("source {name:?} failed to extract: {source}")]
+    SourceExtract {
+        name: String,
+        #[source]
+        source: bootstrapper::archives::ArchiveError,
+    },
+    #[error("dep {name:?} failed to extract: {source}")]
+    DepExtract {
+        name: String,
+        #[source]
+        source: bootstrapper::archives::ArchiveError,
+    },
+}
+
+#[allow(clippy::too_many_arguments)]
 fn build(
     recipe: NamedRecipeVersion,
     source_data: BTreeMap<String, (SourceContents, Vec<u8>)>,
@@ -145,7 +723,8 @@ fn build(
     overlay_data: BTreeMap<PathBuf, Vec<u8>>,
     mut env_data: BTreeMap<String, String>,
     status_updates: std::sync::mpsc::Sender<StatusUpdate>,
-) -> (String, Vec<u8>) {
+    parallelism: usize,
+) -> Result<(String, Vec<u8>), BuildError> {
     let work_dir = tempfile::tempdir_in("ramdir/").unwrap();
     create_dir(work_dir.path().join("dev")).unwrap();
     mknod(
@@ -227,6 +806,23 @@ fn build(
     )
     .unwrap();
     let mut cur_dir = PathBuf::from("/");
+
+    let source_date_epoch = bootstrapper::recipe::source_date_epoch(&recipe.name, &recipe.version);
+    let compression = recipe.compression;
+    let xz_settings = recipe.xz;
+    env_data.insert(
+        "SOURCE_DATE_EPOCH".to_owned(),
+        source_date_epoch.to_string(),
+    );
+    env_data.insert(
+        "datetime".to_owned(),
+        bootstrapper::recipe::format_epoch(source_date_epoch),
+    );
+    env_data.insert(
+        "datetime_utc".to_owned(),
+        bootstrapper::recipe::format_epoch(source_date_epoch),
+    );
+
     if let Some(sources) = recipe.source {
         for (name, source_directive) in sources {
             let (source, data) = source_data
@@ -234,21 +830,25 @@ fn build(
                 .expect(&format!("Missing source {}", name));
             assert!(source_directive.chmod.is_none());
             if let Some(extract) = source_directive.extract {
-                if source.url.ends_with(".zip") {
-                    zip::ZipArchive::new(std::io::Cursor::new(data))
-                        .unwrap()
-                        .filter_extract(
-                            work_dir.path(),
-                            source_extract_filter(
-                                &PathBuf::new(),
-                                &PathBuf::from(extract),
-                                &source_directive.copy,
-                                true,
-                            ),
-                        )
-                } else {
-                    todo!("{}", source.url);
-                }
+                let kind = bootstrapper::archives::ArchiveKind::detect(&source.url)
+                    .or_else(|| bootstrapper::archives::ArchiveKind::sniff(data))
+                    .unwrap_or_else(|| panic!("unsupported source archive format: {}", source.url));
+                bootstrapper::archives::extract_source(
+                    kind,
+                    data,
+                    work_dir.path(),
+                    &ExtractOptions::default(),
+                    source_extract_filter(
+                        &PathBuf::new(),
+                        &PathBuf::from(extract),
+                        &source_directive.copy,
+                        true,
+                    ),
+                )
+                .map_err(|source| BuildError::SourceExtract {
+                    name: name.clone(),
+                    source,
+                })?
             }
             if let Some(noextract) = source_directive.noextract {
                 assert!(source_directive.copy.is_none());
@@ -262,18 +862,25 @@ fn build(
     }
     if let Some(deps) = recipe.deps {
         for dep in deps {
+            let dep_key = format!("{}:{}", dep.name, dep.version);
             let data = dep_data
-                .get(&format!("{}:{}", dep.name, dep.version))
+                .get(&dep_key)
                 .expect(&format!("Missing dep {:?}", dep));
-            tar::Archive::new(std::io::Cursor::new(data)).filter_extract(
-                work_dir.path(),
-                source_extract_filter(
-                    &dep.from.map(|x| PathBuf::from(x)).unwrap_or(PathBuf::new()),
-                    &dep.to.map(|x| PathBuf::from(x)).unwrap_or(PathBuf::new()),
-                    &None,
-                    false,
-                ),
-            )
+            tar::Archive::new(std::io::Cursor::new(data))
+                .filter_extract(
+                    work_dir.path(),
+                    &ExtractOptions::default(),
+                    source_extract_filter(
+                        &dep.from.map(|x| PathBuf::from(x)).unwrap_or(PathBuf::new()),
+                        &dep.to.map(|x| PathBuf::from(x)).unwrap_or(PathBuf::new()),
+                        &None,
+                        false,
+                    ),
+                )
+                .map_err(|source| BuildError::DepExtract {
+                    name: dep_key,
+                    source,
+                })?
         }
     }
     if let Some(_shell) = recipe.shell {
@@ -292,7 +899,7 @@ fn build(
                 .unwrap();
         }
     }
-    let mut join_handles = Vec::new();
+    let mut scheduler = Scheduler::new(parallelism);
     match recipe.build {
         bootstrapper::recipe::RecipeBuildSteps::Single { single } => {
             for step in single {
@@ -302,7 +909,7 @@ fn build(
                     &mut cur_dir,
                     &work_dir,
                     &status_updates,
-                    &mut join_handles,
+                    &mut scheduler,
                 );
             }
         }
@@ -393,7 +1000,7 @@ fn build(
                 &mut cur_dir,
                 &work_dir,
                 &status_updates,
-                &mut join_handles,
+                &mut scheduler,
             );
             cur_dir = PathBuf::from("/steps/").join(&pkg).join("build");
             if let Some(unpack) = unpack {
@@ -409,22 +1016,30 @@ fn build(
                                 &mut cur_dir,
                                 &work_dir,
                                 &status_updates,
-                                &mut join_handles,
+                                &mut scheduler,
                             );
                         }
-                        RecipeBuildStep::Complex { cmd, serial, bash } if cmd == "default" => {
+                        RecipeBuildStep::Complex {
+                            cmd,
+                            serial,
+                            bash,
+                            id,
+                            deps,
+                        } if cmd == "default" => {
                             do_step(
                                 &RecipeBuildStep::Complex {
                                     cmd: "bash -exc '. /steps/helpers.sh; default_src_unpack'"
                                         .to_owned(),
                                     serial,
                                     bash,
+                                    id,
+                                    deps,
                                 },
                                 &mut env_data,
                                 &mut cur_dir,
                                 &work_dir,
                                 &status_updates,
-                                &mut join_handles,
+                                &mut scheduler,
                             );
                         }
                         i => {
@@ -434,7 +1049,7 @@ fn build(
                                 &mut cur_dir,
                                 &work_dir,
                                 &status_updates,
-                                &mut join_handles,
+                                &mut scheduler,
                             );
                         }
                     }
@@ -448,7 +1063,7 @@ fn build(
                     &mut cur_dir,
                     &work_dir,
                     &status_updates,
-                    &mut join_handles,
+                    &mut scheduler,
                 );
                 env_data.insert("dirname".to_owned(), unpack_dirname.clone());
                 cur_dir = cur_dir.join(unpack_dirname);
@@ -466,22 +1081,30 @@ fn build(
                                 &mut cur_dir,
                                 &work_dir,
                                 &status_updates,
-                                &mut join_handles,
+                                &mut scheduler,
                             );
                         }
-                        RecipeBuildStep::Complex { cmd, serial, bash } if cmd == "default" => {
+                        RecipeBuildStep::Complex {
+                            cmd,
+                            serial,
+                            bash,
+                            id,
+                            deps,
+                        } if cmd == "default" => {
                             do_step(
                                 &RecipeBuildStep::Complex {
                                     cmd: "bash -exc '. /steps/helpers.sh; default_src_prepare'"
                                         .to_owned(),
                                     serial,
                                     bash,
+                                    id,
+                                    deps,
                                 },
                                 &mut env_data,
                                 &mut cur_dir,
                                 &work_dir,
                                 &status_updates,
-                                &mut join_handles,
+                                &mut scheduler,
                             );
                         }
                         i => {
@@ -491,7 +1114,7 @@ fn build(
                                 &mut cur_dir,
                                 &work_dir,
                                 &status_updates,
-                                &mut join_handles,
+                                &mut scheduler,
                             );
                         }
                     }
@@ -505,7 +1128,7 @@ fn build(
                     &mut cur_dir,
                     &work_dir,
                     &status_updates,
-                    &mut join_handles,
+                    &mut scheduler,
                 );
             }
             if let Some(configure) = configure {
@@ -521,22 +1144,30 @@ fn build(
                                 &mut cur_dir,
                                 &work_dir,
                                 &status_updates,
-                                &mut join_handles,
+                                &mut scheduler,
                             );
                         }
-                        RecipeBuildStep::Complex { cmd, serial, bash } if cmd == "default" => {
+                        RecipeBuildStep::Complex {
+                            cmd,
+                            serial,
+                            bash,
+                            id,
+                            deps,
+                        } if cmd == "default" => {
                             do_step(
                                 &RecipeBuildStep::Complex {
                                     cmd: "bash -exc '. /steps/helpers.sh; default_src_configure'"
                                         .to_owned(),
                                     serial,
                                     bash,
+                                    id,
+                                    deps,
                                 },
                                 &mut env_data,
                                 &mut cur_dir,
                                 &work_dir,
                                 &status_updates,
-                                &mut join_handles,
+                                &mut scheduler,
                             );
                         }
                         i => {
@@ -546,7 +1177,7 @@ fn build(
                                 &mut cur_dir,
                                 &work_dir,
                                 &status_updates,
-                                &mut join_handles,
+                                &mut scheduler,
                             );
                         }
                     }
@@ -560,7 +1191,7 @@ fn build(
                     &mut cur_dir,
                     &work_dir,
                     &status_updates,
-                    &mut join_handles,
+                    &mut scheduler,
                 );
             }
             if let Some(compile) = compile {
@@ -576,22 +1207,30 @@ fn build(
                                 &mut cur_dir,
                                 &work_dir,
                                 &status_updates,
-                                &mut join_handles,
+                                &mut scheduler,
                             );
                         }
-                        RecipeBuildStep::Complex { cmd, serial, bash } if cmd == "default" => {
+                        RecipeBuildStep::Complex {
+                            cmd,
+                            serial,
+                            bash,
+                            id,
+                            deps,
+                        } if cmd == "default" => {
                             do_step(
                                 &RecipeBuildStep::Complex {
                                     cmd: "bash -exc '. /steps/helpers.sh; default_src_compile'"
                                         .to_owned(),
                                     serial,
                                     bash,
+                                    id,
+                                    deps,
                                 },
                                 &mut env_data,
                                 &mut cur_dir,
                                 &work_dir,
                                 &status_updates,
-                                &mut join_handles,
+                                &mut scheduler,
                             );
                         }
                         i => {
@@ -601,7 +1240,7 @@ fn build(
                                 &mut cur_dir,
                                 &work_dir,
                                 &status_updates,
-                                &mut join_handles,
+                                &mut scheduler,
                             );
                         }
                     }
@@ -615,7 +1254,7 @@ fn build(
                     &mut cur_dir,
                     &work_dir,
                     &status_updates,
-                    &mut join_handles,
+                    &mut scheduler,
                 );
             }
             create_dir_all(work_dir.path().join(sanitize_path(&PathBuf::from(
@@ -635,22 +1274,30 @@ fn build(
                                 &mut cur_dir,
                                 &work_dir,
                                 &status_updates,
-                                &mut join_handles,
+                                &mut scheduler,
                             );
                         }
-                        RecipeBuildStep::Complex { cmd, serial, bash } if cmd == "default" => {
+                        RecipeBuildStep::Complex {
+                            cmd,
+                            serial,
+                            bash,
+                            id,
+                            deps,
+                        } if cmd == "default" => {
                             do_step(
                                 &RecipeBuildStep::Complex {
                                     cmd: "bash -exc '. /steps/helpers.sh; default_src_install'"
                                         .to_owned(),
                                     serial,
                                     bash,
+                                    id,
+                                    deps,
                                 },
                                 &mut env_data,
                                 &mut cur_dir,
                                 &work_dir,
                                 &status_updates,
-                                &mut join_handles,
+                                &mut scheduler,
                             );
                         }
                         i => {
@@ -660,7 +1307,7 @@ fn build(
                                 &mut cur_dir,
                                 &work_dir,
                                 &status_updates,
-                                &mut join_handles,
+                                &mut scheduler,
                             );
                         }
                     }
@@ -674,7 +1321,7 @@ fn build(
                     &mut cur_dir,
                     &work_dir,
                     &status_updates,
-                    &mut join_handles,
+                    &mut scheduler,
                 );
             }
             if let Some(postprocess) = postprocess {
@@ -690,22 +1337,30 @@ fn build(
                                 &mut cur_dir,
                                 &work_dir,
                                 &status_updates,
-                                &mut join_handles,
+                                &mut scheduler,
                             );
                         }
-                        RecipeBuildStep::Complex { cmd, serial, bash } if cmd == "default" => {
+                        RecipeBuildStep::Complex {
+                            cmd,
+                            serial,
+                            bash,
+                            id,
+                            deps,
+                        } if cmd == "default" => {
                             do_step(
                                 &RecipeBuildStep::Complex {
                                     cmd: "bash -exc '. /steps/helpers.sh; default_src_postprocess'"
                                         .to_owned(),
                                     serial,
                                     bash,
+                                    id,
+                                    deps,
                                 },
                                 &mut env_data,
                                 &mut cur_dir,
                                 &work_dir,
                                 &status_updates,
-                                &mut join_handles,
+                                &mut scheduler,
                             );
                         }
                         i => {
@@ -715,7 +1370,7 @@ fn build(
                                 &mut cur_dir,
                                 &work_dir,
                                 &status_updates,
-                                &mut join_handles,
+                                &mut scheduler,
                             );
                         }
                     }
@@ -729,7 +1384,7 @@ fn build(
                     &mut cur_dir,
                     &work_dir,
                     &status_updates,
-                    &mut join_handles,
+                    &mut scheduler,
                 );
             }
             cur_dir = PathBuf::from(env_data.get("DESTDIR").unwrap());
@@ -739,7 +1394,7 @@ fn build(
                 &mut cur_dir,
                 &work_dir,
                 &status_updates,
-                &mut join_handles,
+                &mut scheduler,
             );
             cur_dir = PathBuf::from("/external/repo");
             do_step(
@@ -750,24 +1405,36 @@ fn build(
                 &mut cur_dir,
                 &work_dir,
                 &status_updates,
-                &mut join_handles,
+                &mut scheduler,
             );
         }
     }
-    join_handles
-        .drain(..)
-        .for_each(|mut res| assert!(res.wait().unwrap().success()));
+    scheduler.drain();
     let repo_dir = tempfile::tempdir_in("ramdir/").unwrap();
-    if !recipe.artefacts.is_empty() && recipe.artefacts[0].ends_with(".tar.bz2") {
-        let mut tar = tar::Archive::new(BzDecoder::new(
-            std::fs::File::open(
-                work_dir
-                    .path()
-                    .join(sanitize_path(&PathBuf::from(recipe.artefacts[0].clone()))),
-            )
-            .unwrap(),
-        ));
-        tar.unpack(repo_dir.path()).unwrap();
+    if let Some(seed_artefact) = recipe.artefacts.first() {
+        let seed_path = work_dir
+            .path()
+            .join(sanitize_path(&PathBuf::from(seed_artefact.clone())));
+        if let Ok(seed_data) = std::fs::read(&seed_path) {
+            // Same detect-then-sniff dispatch as source extraction: a repo
+            // seed is just another recipe's packaged artefact, so it can
+            // show up compressed any way `compress_tar` knows how to
+            // produce. An artefact that doesn't look like any known archive
+            // format isn't a repo seed at all, so it's left alone here.
+            if let Some(kind) = bootstrapper::archives::ArchiveKind::detect(seed_artefact)
+                .or_else(|| bootstrapper::archives::ArchiveKind::sniff(&seed_data))
+            {
+                match bootstrapper::archives::decode_archive(kind, &seed_data).unwrap() {
+                    bootstrapper::archives::Archive::Tar(mut tar) => {
+                        tar.unpack(repo_dir.path()).unwrap();
+                    }
+                    _ => panic!(
+                        "repo-seed artefact {} isn't a tar-based archive",
+                        seed_artefact
+                    ),
+                }
+            }
+        }
     }
     let mut tar_writer = tar::Builder::new(Cursor::new(Vec::new()));
     tar_writer.mode(tar::HeaderMode::TimestampDeterministic);
@@ -802,8 +1469,47 @@ fn build(
     umount(&work_dir.path().join("proc")).unwrap();
     umount(&work_dir.path().join("dev/pts")).unwrap();
     tar_writer.finish().unwrap();
-    let tar_buf = tar_writer.into_inner().unwrap().into_inner();
-    let hash = sha256::digest(&tar_buf);
+    let tar_buf = normalize_tar(
+        tar_writer.into_inner().unwrap().into_inner(),
+        source_date_epoch,
+    );
+    let package_buf = bootstrapper::archives::compress_tar(&tar_buf, compression, xz_settings);
+    let hash = sha256::digest(&package_buf);
     status_updates.send(StatusUpdate::Done).unwrap();
-    return (hash, tar_buf);
+    return Ok((hash, package_buf));
+}
+
+/// Re-writes `tar_buf` so the same recipe produces a bit-for-bit identical
+/// archive on every machine and every build: every entry's mtime is clamped
+/// to `SOURCE_DATE_EPOCH`, uid/gid and owner names are zeroed, and entries
+/// are sorted by path so append order (which otherwise follows
+/// filesystem/readdir order) can't perturb the result.
+fn normalize_tar(tar_buf: Vec<u8>, source_date_epoch: u64) -> Vec<u8> {
+    let mut entries: Vec<(PathBuf, tar::Header, Vec<u8>)> = Vec::new();
+    {
+        let mut archive = tar::Archive::new(Cursor::new(&tar_buf));
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().into_owned();
+            let mut header = entry.header().clone();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            let mtime = header.mtime().unwrap_or(source_date_epoch);
+            header.set_mtime(mtime.min(source_date_epoch));
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_username("").unwrap();
+            header.set_groupname("").unwrap();
+            header.set_cksum();
+            entries.push((path, header, data));
+        }
+    }
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut tar_writer = tar::Builder::new(Cursor::new(Vec::new()));
+    for (_, header, data) in &entries {
+        tar_writer.append(header, data.as_slice()).unwrap();
+    }
+    tar_writer.finish().unwrap();
+    tar_writer.into_inner().unwrap().into_inner()
 }
@@ -0,0 +1,215 @@
+use std::path::PathBuf;
+
+use lazy_static::lazy_static;
+
+/// Boundaries a content-defined chunker must respect: never emit a chunk
+/// shorter than `min_size` (except the final one), prefer to cut around
+/// `avg_size`, and force a cut at `max_size` so a pathological input (e.g.
+/// one long run of a single byte) can't produce one unbounded chunk.
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkerConfig {
+    pub min_size: usize,
+    pub avg_size: usize,
+    pub max_size: usize,
+}
+
+impl Default for ChunkerConfig {
+    fn default() -> Self {
+        Self {
+            min_size: 16 * 1024,
+            avg_size: 64 * 1024,
+            max_size: 256 * 1024,
+        }
+    }
+}
+
+lazy_static! {
+    /// Per-byte multipliers for the Gear hash below, derived once from a
+    /// fixed seed via splitmix64 rather than hardcoded literals - the table
+    /// only needs to be the same on every run (so chunk boundaries, and
+    /// therefore dedup, are deterministic), not cryptographically chosen.
+    static ref GEAR_TABLE: [u64; 256] = {
+        let mut table = [0u64; 256];
+        let mut seed: u64 = 0x9E3779B97F4A7C15;
+        for slot in table.iter_mut() {
+            seed = seed.wrapping_add(0x9E3779B97F4A7C15);
+            let mut z = seed;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+            *slot = z ^ (z >> 31);
+        }
+        table
+    };
+}
+
+/// Splits `data` into content-defined chunks: a Gear rolling hash is folded
+/// in one byte at a time, and a cut point is emitted once the window has
+/// grown past `min_size` and the hash's low bits happen to be all zero
+/// (`hash & mask == 0`), or unconditionally once it hits `max_size`. Because
+/// the cut points are a function of the bytes themselves rather than a fixed
+/// offset, inserting or deleting bytes in the middle of an artefact only
+/// reshuffles the chunk(s) touching the edit - everything before and after
+/// still chunks identically, so [`store_chunks`] dedups it for free.
+pub fn chunk_data<'a>(data: &'a [u8], config: &ChunkerConfig) -> Vec<&'a [u8]> {
+    let mask = (config.avg_size as u64).next_power_of_two() - 1;
+    let mut chunks = Vec::new();
+    let mut start = 0usize;
+    let mut hash: u64 = 0;
+    for i in 0..data.len() {
+        hash = (hash << 1).wrapping_add(GEAR_TABLE[data[i] as usize]);
+        let size = i + 1 - start;
+        if size >= config.max_size || (size >= config.min_size && hash & mask == 0) {
+            chunks.push(&data[start..i + 1]);
+            start = i + 1;
+            hash = 0;
+        }
+    }
+    if start < data.len() {
+        chunks.push(&data[start..]);
+    }
+    chunks
+}
+
+fn chunk_dir() -> PathBuf {
+    PathBuf::from("build-cache").join("chunks")
+}
+
+fn chunk_path(hash: &str) -> PathBuf {
+    chunk_dir().join(&hash[0..2]).join(&hash[2..4]).join(hash)
+}
+
+/// Splits `data` into chunks and writes each one, keyed by its own sha256,
+/// to the chunk store if it isn't already there. Returns the chunk hashes in
+/// order, forming the manifest that reconstructs `data` via [`load_chunks`].
+pub fn store_chunks(data: &[u8]) -> Vec<String> {
+    chunk_data(data, &ChunkerConfig::default())
+        .into_iter()
+        .map(|chunk| {
+            let hash = sha256::digest(chunk);
+            let path = chunk_path(&hash);
+            if !path.exists() {
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(&path, chunk).unwrap();
+            }
+            hash
+        })
+        .collect()
+}
+
+/// Reassembles an artefact from an ordered manifest of chunk hashes, as
+/// produced by [`store_chunks`].
+pub fn load_chunks(chunk_hashes: &[String]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for hash in chunk_hashes {
+        out.extend(std::fs::read(chunk_path(hash)).unwrap());
+    }
+    out
+}
+
+/// Writes a manifest (one chunk hash per line) to `manifest_path`, creating
+/// its parent directory as needed - mirrors how `dep_path` lays out
+/// `build-cache/build/xx/yy/<hash>`.
+pub fn write_manifest(manifest_path: &std::path::Path, chunk_hashes: &[String]) {
+    std::fs::create_dir_all(manifest_path.parent().unwrap()).unwrap();
+    std::fs::write(manifest_path, chunk_hashes.join("\n")).unwrap();
+}
+
+/// Reads back a manifest written by [`write_manifest`].
+pub fn read_manifest(manifest_path: &std::path::Path) -> Vec<String> {
+    std::fs::read_to_string(manifest_path)
+        .unwrap()
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Every chunk currently on disk, by hash - the candidate set [`gc`][crate]
+/// callers prune against the live set they compute from `equiv.sled`.
+pub fn all_chunk_hashes() -> Vec<String> {
+    let dir = chunk_dir();
+    if !dir.exists() {
+        return Vec::new();
+    }
+    walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.metadata().map(|m| m.is_file()).unwrap_or(false))
+        .map(|entry| entry.file_name().to_str().unwrap().to_owned())
+        .collect()
+}
+
+/// Deletes `hash` from the chunk store, if present.
+pub fn remove_chunk(hash: &str) {
+    let _ = std::fs::remove_file(chunk_path(hash));
+}
+
+#[test]
+fn test_chunk_data_empty() {
+    assert_eq!(chunk_data(&[], &ChunkerConfig::default()).len(), 0);
+}
+
+#[test]
+fn test_chunk_data_below_min_size_is_one_chunk() {
+    let config = ChunkerConfig {
+        min_size: 16 * 1024,
+        avg_size: 64 * 1024,
+        max_size: 256 * 1024,
+    };
+    let data = vec![0u8; 100];
+    let chunks = chunk_data(&data, &config);
+    assert_eq!(chunks, vec![data.as_slice()]);
+}
+
+#[test]
+fn test_chunk_data_respects_max_size() {
+    let config = ChunkerConfig {
+        min_size: 4,
+        avg_size: 8,
+        max_size: 16,
+    };
+    // After n bytes of a run of one byte the Gear hash is g * (2^n - 1);
+    // 0x7F's table entry is odd, so the hash stays odd and never satisfies
+    // the low-bits-zero cut condition. Without the max_size cutoff this
+    // would collapse into one unbounded chunk.
+    let data = vec![0x7Fu8; 1000];
+    let chunks = chunk_data(&data, &config);
+    assert!(chunks.len() > 1);
+    for chunk in &chunks[..chunks.len() - 1] {
+        assert!(chunk.len() <= config.max_size);
+        assert!(chunk.len() >= config.min_size);
+    }
+}
+
+#[test]
+fn test_chunk_data_never_below_min_size_except_last() {
+    let config = ChunkerConfig {
+        min_size: 8,
+        avg_size: 16,
+        max_size: 64,
+    };
+    let data: Vec<u8> = (0..2000).map(|i| (i % 251) as u8).collect();
+    let chunks = chunk_data(&data, &config);
+    assert!(chunks.len() > 1);
+    for chunk in &chunks[..chunks.len() - 1] {
+        assert!(chunk.len() >= config.min_size);
+    }
+}
+
+#[test]
+fn test_chunk_data_reassembles_to_input() {
+    let config = ChunkerConfig::default();
+    let data: Vec<u8> = (0..500_000).map(|i| (i % 256) as u8).collect();
+    let chunks = chunk_data(&data, &config);
+    let reassembled: Vec<u8> = chunks.into_iter().flatten().copied().collect();
+    assert_eq!(reassembled, data);
+}
+
+#[test]
+fn test_chunk_data_is_deterministic() {
+    let config = ChunkerConfig::default();
+    let data: Vec<u8> = (0..500_000).map(|i| ((i * 7) % 256) as u8).collect();
+    let a: Vec<&[u8]> = chunk_data(&data, &config);
+    let b: Vec<&[u8]> = chunk_data(&data, &config);
+    assert_eq!(a, b);
+}
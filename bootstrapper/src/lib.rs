@@ -1,5 +1,5 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     ffi::OsString,
     io::{Read, Seek},
     path::{Component, Path, PathBuf},
@@ -8,8 +8,10 @@ use std::{
 use regex::Regex;
 
 pub mod archives;
+pub mod chunkstore;
 pub mod network;
 pub mod recipe;
+pub mod recipe_lang;
 pub mod source;
 
 pub trait ESPIN {
@@ -141,37 +143,139 @@ pub fn sanitize_path(p: &Path) -> PathBuf {
     }
 }
 
-pub fn env_substitute(line: &str, env: &BTreeMap<String, String>) -> String {
+#[derive(Debug, thiserror::Error)]
+pub enum EnvSubstituteError {
+    #[error("no env var found: {0}")]
+    MissingVar(String),
+}
+
+/// Expand `$VAR` and `${VAR}` references against `env`, plus the common
+/// bash parameter-expansion forms: `${VAR:-default}` (default if unset or
+/// empty), `${VAR:+alt}` (alt only if set and non-empty), and `${VAR:=default}`
+/// (same substitution as `:-`; unlike bash this does not write the default
+/// back into `env`, since callers hand us a borrowed environment). Defaults
+/// may themselves reference other variables (`${VAR:-$OTHER}`) - they're
+/// spliced in verbatim and picked up by the next pass of the fixpoint loop
+/// below. An unset variable with no default is a recoverable error rather
+/// than a panic, so callers can decide how to handle a missing binding.
+pub fn env_substitute(
+    line: &str,
+    env: &BTreeMap<String, String>,
+) -> Result<String, EnvSubstituteError> {
     let mut line = line.to_owned();
     loop {
         let mut changed = false;
-        let simple_re = Regex::new(r"(^|[^\\])\$([a-zA-Z_][a-zA-Z_0-9]*)").unwrap();
-        let brace_re = Regex::new(r"(^|[^\\])\$\{([a-zA-Z_][a-zA-Z_0-9]*)\}").unwrap();
-        line = simple_re
+        let mut missing = None;
+
+        let brace_re = Regex::new(
+            r"(^|[^\\])\$\{([a-zA-Z_][a-zA-Z_0-9]*)(?:(:-|:\+|:=)((?:[^{}]|\{[^{}]*\})*))?\}",
+        )
+        .unwrap();
+        line = brace_re
             .replace_all(&line, |captures: &regex::Captures<'_>| {
                 changed = true;
-                captures.get(1).unwrap().as_str().to_owned()
-                    + env.get(captures.get(2).unwrap().as_str()).expect(&format!(
-                        "no env var found: {}",
-                        captures.get(2).unwrap().as_str()
-                    ))
+                let lead = captures.get(1).unwrap().as_str();
+                let name = captures.get(2).unwrap().as_str();
+                let op = captures.get(3).map(|m| m.as_str());
+                let operand = captures.get(4).map(|m| m.as_str()).unwrap_or("");
+                let value = env.get(name).filter(|v| !v.is_empty());
+                let expanded = match op {
+                    Some(":-") | Some(":=") => value.cloned().unwrap_or_else(|| operand.to_owned()),
+                    Some(":+") => {
+                        if value.is_some() {
+                            operand.to_owned()
+                        } else {
+                            String::new()
+                        }
+                    }
+                    _ => match env.get(name) {
+                        Some(v) => v.to_owned(),
+                        None => {
+                            missing = Some(name.to_owned());
+                            String::new()
+                        }
+                    },
+                };
+                format!("{lead}{expanded}")
             })
             .to_string();
-        line = brace_re
+        if let Some(name) = missing {
+            return Err(EnvSubstituteError::MissingVar(name));
+        }
+
+        let simple_re = Regex::new(r"(^|[^\\])\$([a-zA-Z_][a-zA-Z_0-9]*)").unwrap();
+        line = simple_re
             .replace_all(&line, |captures: &regex::Captures<'_>| {
                 changed = true;
-                captures.get(1).unwrap().as_str().to_owned()
-                    + env.get(captures.get(2).unwrap().as_str()).unwrap()
+                let lead = captures.get(1).unwrap().as_str();
+                let name = captures.get(2).unwrap().as_str();
+                match env.get(name) {
+                    Some(v) => format!("{lead}{v}"),
+                    None => {
+                        missing = Some(name.to_owned());
+                        lead.to_owned()
+                    }
+                }
             })
             .to_string();
+        if let Some(name) = missing {
+            return Err(EnvSubstituteError::MissingVar(name));
+        }
+
         if !changed {
-            return line;
+            return Ok(line);
         }
     }
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum EnvLayerError {
+    #[error(transparent)]
+    Substitute(#[from] EnvSubstituteError),
+    #[error("%include cycle at {0:?}")]
+    IncludeCycle(PathBuf),
+}
+
+/// Apply one env-file layer onto `env` in place, line by line, so later
+/// lines (and anything `%include`d) can reference earlier definitions via
+/// [`env_substitute`]. Inspired by Mercurial's config layering, two
+/// directives are recognised besides plain `KEY=VALUE` lines:
+/// - `%include <path>`, resolved relative to the including file's directory
+///   and applied as its own nested layer. `visited` tracks the files
+///   currently being expanded (not just already-seen ones) so a genuine
+///   cycle is rejected while a diamond-shaped re-include of the same
+///   fragment from two different layers is not.
+/// - `%unset <KEY>`, dropping a variable inherited from an outer layer.
+pub fn apply_env_layer(
+    path: &Path,
+    env: &mut BTreeMap<String, String>,
+    visited: &mut BTreeSet<PathBuf>,
+) -> Result<(), EnvLayerError> {
+    let canonical = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+    if !visited.insert(canonical.clone()) {
+        return Err(EnvLayerError::IncludeCycle(canonical));
+    }
+    for line in std::fs::read_to_string(path).unwrap().lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(included) = line.strip_prefix("%include ") {
+            apply_env_layer(&path.parent().unwrap().join(included.trim()), env, visited)?;
+        } else if let Some(key) = line.strip_prefix("%unset ") {
+            env.remove(key.trim());
+        } else {
+            let (key, value) = line.split_once('=').unwrap();
+            let value = env_substitute(value.trim_matches('"'), env)?;
+            env.insert(key.to_owned(), value);
+        }
+    }
+    visited.remove(&canonical);
+    Ok(())
+}
+
 #[repr(u8)]
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum WorkerStatus {
     ReadyForWork,
     ReadyForSource,
@@ -185,6 +289,14 @@ pub enum WorkerStatus {
     NeedOverlay,
     ReadyForEnvs,
     BuildComplete,
+    /// Precedes a [`crate::network::StatusUpdate`] frame: a worker can send
+    /// any number of these while a build is in progress, ahead of the
+    /// eventual `BuildComplete`.
+    StatusUpdate,
+    /// Sent instead of `BuildComplete` when the build errored out (e.g. a
+    /// source failed to extract) - precedes a length-prefixed UTF-8 error
+    /// message, in place of the hash+archive that would otherwise follow.
+    BuildFailed,
 }
 
 #[test]
@@ -202,3 +314,68 @@ fn test_sanitize_path_dot() {
 fn test_sanitize_path_dotdot() {
     assert_eq!(sanitize_path(&PathBuf::from("..")), PathBuf::new())
 }
+
+#[test]
+fn test_env_substitute_simple_and_braced() {
+    let env = BTreeMap::from([("FOO".to_owned(), "bar".to_owned())]);
+    assert_eq!(env_substitute("$FOO", &env).unwrap(), "bar");
+    assert_eq!(env_substitute("${FOO}", &env).unwrap(), "bar");
+    assert_eq!(env_substitute("x${FOO}y", &env).unwrap(), "xbary");
+}
+
+#[test]
+fn test_env_substitute_missing_var_errors() {
+    let env = BTreeMap::new();
+    assert!(matches!(
+        env_substitute("$MISSING", &env),
+        Err(EnvSubstituteError::MissingVar(name)) if name == "MISSING"
+    ));
+    assert!(matches!(
+        env_substitute("${MISSING}", &env),
+        Err(EnvSubstituteError::MissingVar(name)) if name == "MISSING"
+    ));
+}
+
+#[test]
+fn test_env_substitute_default_op() {
+    let env = BTreeMap::new();
+    assert_eq!(
+        env_substitute("${MISSING:-fallback}", &env).unwrap(),
+        "fallback"
+    );
+    let env = BTreeMap::from([("FOO".to_owned(), String::new())]);
+    assert_eq!(
+        env_substitute("${FOO:-fallback}", &env).unwrap(),
+        "fallback"
+    );
+    let env = BTreeMap::from([("FOO".to_owned(), "set".to_owned())]);
+    assert_eq!(env_substitute("${FOO:-fallback}", &env).unwrap(), "set");
+}
+
+#[test]
+fn test_env_substitute_alternate_op() {
+    let env = BTreeMap::from([("FOO".to_owned(), "set".to_owned())]);
+    assert_eq!(env_substitute("${FOO:+alt}", &env).unwrap(), "alt");
+    let env = BTreeMap::new();
+    assert_eq!(env_substitute("${FOO:+alt}", &env).unwrap(), "");
+}
+
+#[test]
+fn test_env_substitute_assign_op() {
+    let env = BTreeMap::new();
+    assert_eq!(
+        env_substitute("${FOO:=fallback}", &env).unwrap(),
+        "fallback"
+    );
+    // `:=` doesn't write the default back into a borrowed env.
+    assert!(!env.contains_key("FOO"));
+}
+
+#[test]
+fn test_env_substitute_nested_default() {
+    let env = BTreeMap::from([("OTHER".to_owned(), "other-value".to_owned())]);
+    assert_eq!(
+        env_substitute("${MISSING:-$OTHER}", &env).unwrap(),
+        "other-value"
+    );
+}
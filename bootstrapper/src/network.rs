@@ -1,5 +1,5 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     ffi::OsStr,
     io::{Read, Write},
     net::TcpStream,
@@ -8,12 +8,89 @@ use std::{
 };
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    recipe::{NamedRecipeVersion, SourceContents},
+    apply_env_layer,
+    recipe::{DepSpec, NamedRecipeVersion, SourceContents},
     WorkerStatus,
 };
 
+/// Live progress for a single build step, sent by a worker as it runs a
+/// recipe so the coordinator can show per-step logs instead of only the
+/// final archive. Each variant mirrors a stage of `do_step`/`run_command` in
+/// the worker binary.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum StatusUpdate {
+    /// A step's command line, once attributes are parsed and arguments are
+    /// expanded.
+    CommandRun(Vec<String>),
+    /// One line of the step's captured stdout.
+    CommandOut(String),
+    /// One line of the step's captured stderr.
+    CommandError(String),
+    /// The step's process exited with this code.
+    CommandDone(i32),
+    /// The whole recipe finished running its build steps.
+    Done,
+}
+
+/// Send one [`StatusUpdate`] frame: a `WorkerStatus::StatusUpdate` tag byte,
+/// then a length-prefixed YAML payload. A worker may send any number of
+/// these before its final `write_archive` call.
+pub fn write_status_update(stream: &mut TcpStream, update: &StatusUpdate) {
+    stream.write_u8(WorkerStatus::StatusUpdate as u8).unwrap();
+    let buf = serde_yaml::to_string(update).unwrap().into_bytes();
+    stream
+        .write_u32::<BigEndian>(buf.len().try_into().unwrap())
+        .unwrap();
+    stream.write_all(&buf).unwrap();
+}
+
+/// Read one [`StatusUpdate`] frame's body. The caller must have already
+/// consumed the `WorkerStatus::StatusUpdate` tag byte that precedes it, the
+/// same way the reader of a [`write_archive`] frame consumes `BuildComplete`
+/// first.
+pub fn read_status_update(stream: &mut TcpStream) -> StatusUpdate {
+    let len = stream.read_u32::<BigEndian>().unwrap();
+    let mut buf = vec![0u8; len.try_into().unwrap()];
+    stream.read_exact(&mut buf).unwrap();
+    serde_yaml::from_slice(&buf).unwrap()
+}
+
+/// What a worker asserts about itself the moment it connects, before it
+/// ever asks for work: its target architecture, kernel release, and the
+/// host tools it has available (name -> version). Lets the coordinator
+/// route a recipe's `arch`/`requires` constraints to a worker that can
+/// actually satisfy them, instead of assuming every worker is
+/// interchangeable.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct WorkerCapabilities {
+    pub arch: String,
+    pub kernel: String,
+    #[serde(default)]
+    pub tools: BTreeMap<String, String>,
+}
+
+/// Send a worker's [`WorkerCapabilities`] as the first frame on a fresh
+/// connection, before its `ReadyForWork` handshake loop begins.
+pub fn write_capabilities(stream: &mut TcpStream, caps: &WorkerCapabilities) {
+    let buf = serde_yaml::to_string(caps).unwrap().into_bytes();
+    stream
+        .write_u32::<BigEndian>(buf.len().try_into().unwrap())
+        .unwrap();
+    stream.write_all(&buf).unwrap();
+}
+
+/// Read the [`WorkerCapabilities`] frame a worker sends right after
+/// connecting, before its first `ReadyForWork` ping.
+pub fn read_capabilities(stream: &mut TcpStream) -> WorkerCapabilities {
+    let len = stream.read_u32::<BigEndian>().unwrap();
+    let mut buf = vec![0u8; len.try_into().unwrap()];
+    stream.read_exact(&mut buf).unwrap();
+    serde_yaml::from_slice(&buf).unwrap()
+}
+
 pub fn read_recipe(stream: &mut TcpStream) -> NamedRecipeVersion {
     let recipe_len = stream.read_u64::<byteorder::BigEndian>().unwrap();
     let mut recipe_buf = vec![0u8; recipe_len.try_into().unwrap()];
@@ -218,6 +295,140 @@ pub fn write_envs(stream: &mut TcpStream, envs: BTreeMap<String, String>) {
     }
 }
 
+/// Where a worker pulls a recipe's inputs from: the TCP coordinator (the
+/// normal case, implemented by delegating to the free functions above) or a
+/// filesystem [`LocalSource`], so a single recipe can be built and debugged
+/// without standing up a `server`.
+pub trait RecipeSource {
+    fn read_recipe(&mut self) -> NamedRecipeVersion;
+    fn read_sources(&mut self) -> BTreeMap<String, (SourceContents, Vec<u8>)>;
+    fn read_deps(&mut self) -> BTreeMap<String, Vec<u8>>;
+    fn read_overlays(&mut self) -> BTreeMap<PathBuf, Vec<u8>>;
+    fn read_envs(&mut self) -> BTreeMap<String, String>;
+}
+
+impl RecipeSource for TcpStream {
+    fn read_recipe(&mut self) -> NamedRecipeVersion {
+        read_recipe(self)
+    }
+    fn read_sources(&mut self) -> BTreeMap<String, (SourceContents, Vec<u8>)> {
+        read_sources(self)
+    }
+    fn read_deps(&mut self) -> BTreeMap<String, Vec<u8>> {
+        read_deps(self)
+    }
+    fn read_overlays(&mut self) -> BTreeMap<PathBuf, Vec<u8>> {
+        read_overlays(self)
+    }
+    fn read_envs(&mut self) -> BTreeMap<String, String> {
+        read_envs(self)
+    }
+}
+
+/// A [`RecipeSource`] backed by local paths instead of a coordinator socket:
+/// the recipe from `recipe_path` (or stdin, if `None`), and already-fetched
+/// sources/deps/overlays from local directories, each keyed/named the same
+/// way the coordinator would send them. `envs_path`'s directory chain is
+/// walked and layered the same way `build_recipe` walks the recipes root,
+/// via `apply_env_layer`. Any other field left `None` is treated as empty,
+/// matching a recipe with no sources, no deps, no overlay and no env files.
+pub struct LocalSource {
+    pub recipe_path: Option<PathBuf>,
+    pub sources_dir: Option<PathBuf>,
+    pub deps_dir: Option<PathBuf>,
+    pub overlays_dir: Option<PathBuf>,
+    pub envs_path: Option<PathBuf>,
+}
+
+impl RecipeSource for LocalSource {
+    fn read_recipe(&mut self) -> NamedRecipeVersion {
+        let buf = match &self.recipe_path {
+            Some(path) => std::fs::read(path).unwrap(),
+            None => {
+                let mut buf = Vec::new();
+                std::io::stdin().read_to_end(&mut buf).unwrap();
+                buf
+            }
+        };
+        serde_yaml::from_slice(&buf).unwrap()
+    }
+
+    fn read_sources(&mut self) -> BTreeMap<String, (SourceContents, Vec<u8>)> {
+        let mut source_data = BTreeMap::new();
+        let Some(dir) = &self.sources_dir else {
+            return source_data;
+        };
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let entry = entry.unwrap();
+            let name = entry.file_name().into_string().unwrap();
+            let data = std::fs::read(entry.path()).unwrap();
+            let contents = SourceContents {
+                url: name.clone(),
+                sha: sha256::digest(&data),
+                mirrors: Vec::new(),
+            };
+            source_data.insert(name, (contents, data));
+        }
+        source_data
+    }
+
+    fn read_deps(&mut self) -> BTreeMap<String, Vec<u8>> {
+        let mut dep_data = BTreeMap::new();
+        let Some(dir) = &self.deps_dir else {
+            return dep_data;
+        };
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let entry = entry.unwrap();
+            let name = entry.file_name().into_string().unwrap();
+            dep_data.insert(name, std::fs::read(entry.path()).unwrap());
+        }
+        dep_data
+    }
+
+    fn read_overlays(&mut self) -> BTreeMap<PathBuf, Vec<u8>> {
+        let mut overlay_data = BTreeMap::new();
+        let Some(dir) = &self.overlays_dir else {
+            return overlay_data;
+        };
+        for entry in walkdir::WalkDir::new(dir) {
+            let entry = entry.unwrap();
+            if entry.metadata().unwrap().is_file() {
+                let rel = entry.path().strip_prefix(dir).unwrap().to_owned();
+                overlay_data.insert(rel, std::fs::read(entry.path()).unwrap());
+            }
+        }
+        overlay_data
+    }
+
+    fn read_envs(&mut self) -> BTreeMap<String, String> {
+        let mut env_data = BTreeMap::new();
+        let Some(path) = &self.envs_path else {
+            return env_data;
+        };
+        let mut visited = BTreeSet::new();
+        // Mirror `build_recipe`'s walk from the recipes root down to the
+        // recipe's own directory: apply every ancestor directory's `env`
+        // file (outer to inner), then `envs_path` itself as the innermost,
+        // most specific layer - so `--local` resolves the same merged env
+        // (including any `%include`/`%unset` directives) the coordinator
+        // would for the same recipe, instead of a single flat file.
+        let mut layer_dir = PathBuf::new();
+        if let Some(parent) = path.parent() {
+            for component in parent.components() {
+                layer_dir.push(component);
+                let ancestor_env = layer_dir.join("env");
+                if ancestor_env != *path && ancestor_env.exists() {
+                    apply_env_layer(&ancestor_env, &mut env_data, &mut visited).unwrap();
+                }
+            }
+        }
+        if path.exists() {
+            apply_env_layer(path, &mut env_data, &mut visited).unwrap();
+        }
+        env_data
+    }
+}
+
 pub fn write_archive(stream: &mut TcpStream, hash: &str, archive: &[u8]) {
     stream.write_u8(WorkerStatus::BuildComplete as u8).unwrap();
     assert_eq!(hash.as_bytes().len(), 64);
@@ -227,3 +438,27 @@ pub fn write_archive(stream: &mut TcpStream, hash: &str, archive: &[u8]) {
         .unwrap();
     stream.write_all(&archive).unwrap();
 }
+
+/// Send a `WorkerStatus::BuildFailed` frame in place of [`write_archive`]:
+/// a tag byte followed by a length-prefixed UTF-8 error message, so a
+/// coordinator waiting on the usual hash+archive can tell a build genuinely
+/// failed (a source that wouldn't extract, say) from one still in progress.
+pub fn write_build_failed(stream: &mut TcpStream, message: &str) {
+    stream.write_u8(WorkerStatus::BuildFailed as u8).unwrap();
+    let buf = message.as_bytes();
+    stream
+        .write_u32::<BigEndian>(buf.len().try_into().unwrap())
+        .unwrap();
+    stream.write_all(buf).unwrap();
+}
+
+/// Read one `BuildFailed` frame's body. The caller must have already
+/// consumed the `WorkerStatus::BuildFailed` tag byte that precedes it, the
+/// same way the reader of a [`write_archive`] frame consumes `BuildComplete`
+/// first.
+pub fn read_build_failed(stream: &mut TcpStream) -> String {
+    let len = stream.read_u32::<BigEndian>().unwrap();
+    let mut buf = vec![0u8; len.try_into().unwrap()];
+    stream.read_exact(&mut buf).unwrap();
+    String::from_utf8_lossy(&buf).into_owned()
+}
@@ -10,6 +10,9 @@ use lazy_static::lazy_static;
 
 use serde::{Deserialize, Serialize};
 
+use crate::archives::{Compression, XzSettings};
+use crate::network::WorkerCapabilities;
+
 lazy_static! {
     pub static ref SOURCES: BTreeMap<String, SourceContents> = load_sources();
     static ref EQUIV_CACHE: lockfree::map::Map<(String, String), String> =
@@ -45,6 +48,44 @@ pub fn get_depd_hash(name: &str, version: &str, salt: &str) -> Option<String> {
     Some(sha256::digest(recipe_hash))
 }
 
+/// A stable Unix timestamp for a recipe, derived from its name and version
+/// rather than the wall clock - so the same recipe always yields the same
+/// `SOURCE_DATE_EPOCH`, and the archive it produces is bit-for-bit
+/// reproducible regardless of when or where it's built.
+pub fn source_date_epoch(name: &str, version: &str) -> u64 {
+    let digest = sha256::digest(format!("{}:{}", name, version));
+    u64::from_str_radix(&digest[0..15], 16).unwrap() % 2_000_000_000
+}
+
+/// Formats a Unix timestamp as `YYYY-MM-DD HH:MM:SS` UTC, via the standard
+/// days-since-epoch civil calendar conversion - so recipes that want a
+/// `datetime`/`datetime_utc` stamp can get one derived from
+/// `SOURCE_DATE_EPOCH` instead of the wall clock, without this crate taking
+/// on a date/time dependency just for it.
+pub fn format_epoch(epoch: u64) -> String {
+    let days = (epoch / 86400) as i64;
+    let time_of_day = epoch % 86400;
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = (z - era * 146097) as u64;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let y = yoe as i64 + era * 400;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = if m <= 2 { y + 1 } else { y };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        y,
+        m,
+        d,
+        time_of_day / 3600,
+        (time_of_day % 3600) / 60,
+        time_of_day % 60
+    )
+}
+
 pub fn get_equiv_hash(name: &str, version: &str, salt: &str) -> Option<String> {
     if let Some(hash) = EQUIV_CACHE.get(&(name.to_owned(), version.to_owned())) {
         return Some(hash.1.clone());
@@ -68,6 +109,13 @@ pub fn get_equiv_hash(name: &str, version: &str, salt: &str) -> Option<String> {
 pub struct Source {
     pub extract: Option<String>,
     pub noextract: Option<String>,
+    /// `.gitignore`-style patterns deciding which extracted paths are kept.
+    /// A bare pattern excludes the paths it matches; a `!`-prefixed pattern
+    /// re-includes paths an earlier pattern excluded, e.g.
+    /// `["src/**/*.rs", "!src/tests/**"]` drops every `.rs` file under `src/`
+    /// except those under `src/tests/`, and keeps everything else. `None`
+    /// keeps everything. See
+    /// [`crate::archives::source_extract_filter`].
     pub copy: Option<Vec<String>>,
     pub chmod: Option<String>,
 }
@@ -75,7 +123,22 @@ pub struct Source {
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct SourceContents {
     pub url: String,
+    /// A digest, optionally algorithm-tagged as `sha256:...`, `sha512:...` or
+    /// `blake3:...`. A bare 64-hex-character string is treated as `sha256`
+    /// for backward compatibility with existing `sources.yaml` entries.
     pub sha: String,
+    /// Additional URLs tried, in order, after `url`, until one yields bytes
+    /// matching `sha`. Lets a recipe author route around a dead upstream.
+    #[serde(default)]
+    pub mirrors: Vec<String>,
+}
+
+impl SourceContents {
+    /// All URLs to try, in fallback order: the primary `url` first, then
+    /// each configured mirror.
+    pub fn urls(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.url).chain(self.mirrors.iter())
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -115,6 +178,15 @@ pub enum RecipeBuildStep {
         serial: bool,
         #[serde(default = "_default_false")]
         bash: bool,
+        /// Name other steps can reference from `deps` to wait on this one.
+        /// Ignored (along with `deps`) for `Simple` steps, which have no way
+        /// to be named.
+        #[serde(default)]
+        id: Option<String>,
+        /// Step `id`s this step must wait on before it runs, in addition to
+        /// whatever `serial` implies. See `worker`'s `Scheduler`.
+        #[serde(default)]
+        deps: Vec<String>,
     },
 }
 
@@ -171,6 +243,21 @@ pub struct RecipeVersion {
     pub mkdirs: Option<Vec<String>>,
     pub build: RecipeBuildSteps,
     pub artefacts: Vec<String>,
+    /// How the final artefact tar is compressed. See
+    /// [`crate::archives::compress_tar`].
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default)]
+    pub xz: XzSettings,
+    /// Target architecture this recipe must be built on, matched against a
+    /// worker's advertised [`WorkerCapabilities::arch`]. `None` means it can
+    /// run on any worker.
+    #[serde(default)]
+    pub arch: Option<String>,
+    /// Host tools (by name) a worker must advertise in
+    /// [`WorkerCapabilities::tools`] to build this recipe.
+    #[serde(default)]
+    pub requires: Vec<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -183,15 +270,41 @@ pub struct NamedRecipeVersion {
     pub mkdirs: Option<Vec<String>>,
     pub build: RecipeBuildSteps,
     pub artefacts: Vec<String>,
+    pub compression: Compression,
+    pub xz: XzSettings,
+    pub arch: Option<String>,
+    pub requires: Vec<String>,
 }
 
 impl NamedRecipeVersion {
+    /// Whether a worker advertising `caps` can build this recipe: its `arch`
+    /// (if set) must match exactly, and every tool this recipe `requires`
+    /// must be among the worker's advertised tools.
+    pub fn compatible_with(&self, caps: &WorkerCapabilities) -> bool {
+        if let Some(arch) = &self.arch {
+            if arch != &caps.arch {
+                return false;
+            }
+        }
+        self.requires
+            .iter()
+            .all(|tool| caps.tools.contains_key(tool))
+    }
+
     pub fn load_by_name(name: &str) -> Self {
         let (target, version) = name.split_once(':').unwrap();
         Self::load_by_target_version(target, version)
     }
     pub fn load_by_target_version(target: &str, version: &str) -> Self {
-        let rv: RecipeVersion = serde_yaml::from_reader(std::fs::File::open(PathBuf::from("recipes").join(target).join(format!("{}.yaml",version))).unwrap()).unwrap();
+        let rv: RecipeVersion = serde_yaml::from_reader(
+            std::fs::File::open(
+                PathBuf::from("recipes")
+                    .join(target)
+                    .join(format!("{}.yaml", version)),
+            )
+            .unwrap(),
+        )
+        .unwrap();
         NamedRecipeVersion {
             name: target.to_owned(),
             version: version.to_owned(),
@@ -201,6 +314,10 @@ impl NamedRecipeVersion {
             mkdirs: rv.mkdirs,
             build: rv.build,
             artefacts: rv.artefacts,
+            compression: rv.compression,
+            xz: rv.xz,
+            arch: rv.arch,
+            requires: rv.requires,
         }
     }
 }
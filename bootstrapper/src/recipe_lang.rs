@@ -0,0 +1,574 @@
+//! A small hand-written parser for build-step command lines, replacing
+//! `shlex::split` plus textual [`crate::env_substitute`] in `do_step`.
+//!
+//! The old approach tokenized the raw line with `shlex`, then expanded `$var`
+//! references in the already-split pieces - or, for assignments, expanded
+//! the whole line and just looked for the first `=`. Expanding text that's
+//! already been split (or never split at all) can't know that a substituted
+//! value containing a space was meant to stay one argument, and "does the
+//! first word contain `=`" is a fragile stand-in for a real grammar.
+//!
+//! Here, tokenizing and interpolation happen together in one pass: each
+//! argument parses into an [`Expr`], a sequence of literal text and
+//! [`ExprPart::Var`] references, and [`eval_expr`] expands it into a single
+//! `String` without ever re-splitting the result. The grammar itself is
+//! PEG-style (ordered choice between `cd` / assignment / command, then
+//! between quoted/interpolated/bare text for each argument) even though it's
+//! implemented as a plain recursive-descent parser rather than through a
+//! grammar-macro crate.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseError {
+    #[error("unexpected end of input while parsing {0}")]
+    UnexpectedEof(&'static str),
+    #[error("unterminated {0} starting at byte {1}")]
+    Unterminated(&'static str, usize),
+    #[error("expected an identifier at byte {0}")]
+    ExpectedIdent(usize),
+    #[error("unexpected character {0:?} at byte {1}")]
+    Unexpected(char, usize),
+    #[error("empty command")]
+    EmptyCommand,
+    #[error("unknown step attribute {0:?} at byte {1}")]
+    UnknownAttribute(String, usize),
+    #[error("attribute {0:?} at byte {1} does not take a value")]
+    UnexpectedAttributeValue(&'static str, usize),
+    #[error("attribute {0:?} at byte {1} requires a value")]
+    MissingAttributeValue(&'static str, usize),
+    #[error("invalid value {0:?} for attribute {1:?} at byte {2}")]
+    InvalidAttributeValue(String, &'static str, usize),
+}
+
+/// Per-step execution policy, parsed by [`parse_attrs`] from leading
+/// `[attr]` / `[attr=value]` annotations on a build-step line - e.g.
+/// `[retry=3][allow-network] curl -O $URL`. Threaded through `do_step` so a
+/// recipe author can declare how a step's failure should be handled instead
+/// of scattering retry loops and `|| true` into commands.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StepAttrs {
+    /// `[allow-network]`: keep the step in the host's network namespace
+    /// instead of the isolated one chroothelper otherwise sets up.
+    #[serde(default)]
+    pub allow_network: bool,
+    /// `[no-chroot]`: run the step against the real root filesystem instead
+    /// of chrooting into the build dir first.
+    #[serde(default)]
+    pub no_chroot: bool,
+    /// `[ignore-errors]`: a nonzero exit is logged but doesn't abort the
+    /// build.
+    #[serde(default)]
+    pub ignore_errors: bool,
+    /// `[retry=N]`: attempt the step up to `N` additional times after an
+    /// initial failure.
+    #[serde(default)]
+    pub retry: u32,
+    /// `[timeout=SECONDS]`: kill the step's process if it's still running
+    /// after this many seconds.
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+}
+
+impl Default for StepAttrs {
+    fn default() -> Self {
+        Self {
+            allow_network: false,
+            no_chroot: false,
+            ignore_errors: false,
+            retry: 0,
+            timeout_secs: None,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum EvalError {
+    #[error("no env var found: {0}")]
+    MissingVar(String),
+}
+
+/// One parsed build step - the typed equivalent of the `k=v` / `cd` /
+/// bare-command string matching `do_step` used to do by hand.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    /// `NAME=value...`: an identifier immediately followed by `=`, with the
+    /// rest of the line (which may itself contain spaces) as the value.
+    Assignment { name: String, value: Expr },
+    /// `cd <path>`.
+    Cd { path: Expr },
+    /// A command invocation and its arguments, each independently
+    /// interpolated.
+    Command { args: Vec<Expr> },
+}
+
+/// An argument or value, as a concatenation of literal text and variable
+/// interpolations - e.g. `foo-${VER}.tar.gz` parses to three parts.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Expr(pub Vec<ExprPart>);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprPart {
+    Literal(String),
+    Var { name: String, op: Option<VarOp> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VarOp {
+    /// `${VAR:-default}`: `default` if `VAR` is unset or empty.
+    Default(Expr),
+    /// `${VAR:+alt}`: `alt` if `VAR` is set and non-empty, else empty.
+    Alternate(Expr),
+    /// `${VAR:=default}`: same substitution as `Default`. Unlike bash this
+    /// does not write the default back into the environment, since callers
+    /// hand us a borrowed `env`.
+    Assign(Expr),
+}
+
+/// Parse any leading `[attr]` / `[attr=value]` annotations off `line`,
+/// returning the parsed [`StepAttrs`] and the remainder of the line (with
+/// the annotations and any whitespace immediately after them stripped).
+/// Unannotated lines parse to `StepAttrs::default()` unchanged.
+pub fn parse_attrs(line: &str) -> Result<(StepAttrs, &str), ParseError> {
+    let mut p = Parser::new(line);
+    let mut attrs = StepAttrs::default();
+    loop {
+        p.skip_ws();
+        let save = p.pos;
+        if !p.eat('[') {
+            p.pos = save;
+            break;
+        }
+        let start = p.pos;
+        let name = p.parse_attr_name()?;
+        let value = if p.eat('=') {
+            Some(p.parse_expr_until(|c| c == ']')?)
+        } else {
+            None
+        };
+        if !p.eat(']') {
+            return Err(ParseError::Unterminated("[...]", start));
+        }
+        match (name.as_str(), value) {
+            ("allow-network", None) => attrs.allow_network = true,
+            ("no-chroot", None) => attrs.no_chroot = true,
+            ("ignore-errors", None) => attrs.ignore_errors = true,
+            ("retry", Some(v)) => {
+                let v = eval_expr(&v, &BTreeMap::new()).unwrap_or(String::new());
+                attrs.retry = v
+                    .parse()
+                    .map_err(|_| ParseError::InvalidAttributeValue(v, "retry", start))?;
+            }
+            ("timeout", Some(v)) => {
+                let v = eval_expr(&v, &BTreeMap::new()).unwrap_or(String::new());
+                attrs.timeout_secs = Some(
+                    v.parse()
+                        .map_err(|_| ParseError::InvalidAttributeValue(v, "timeout", start))?,
+                );
+            }
+            ("allow-network", Some(_)) => {
+                return Err(ParseError::UnexpectedAttributeValue("allow-network", start))
+            }
+            ("no-chroot", Some(_)) => {
+                return Err(ParseError::UnexpectedAttributeValue("no-chroot", start))
+            }
+            ("ignore-errors", Some(_)) => {
+                return Err(ParseError::UnexpectedAttributeValue("ignore-errors", start))
+            }
+            ("retry", None) => return Err(ParseError::MissingAttributeValue("retry", start)),
+            ("timeout", None) => return Err(ParseError::MissingAttributeValue("timeout", start)),
+            _ => return Err(ParseError::UnknownAttribute(name, start)),
+        }
+    }
+    Ok((attrs, p.rest()))
+}
+
+/// Parse a single build-step line into a [`Step`]. Callers should strip any
+/// leading attributes with [`parse_attrs`] first - this parses only the
+/// command/assignment/`cd` grammar.
+pub fn parse_step(line: &str) -> Result<Step, ParseError> {
+    let mut p = Parser::new(line);
+    p.skip_ws();
+
+    if p.eat_word("cd") {
+        p.skip_ws();
+        let path = p.parse_arg()?;
+        return Ok(Step::Cd { path });
+    }
+
+    let save = p.pos;
+    if let Ok(name) = p.parse_ident() {
+        if p.eat('=') {
+            let value = p.parse_expr_until(|_| false)?;
+            return Ok(Step::Assignment { name, value });
+        }
+    }
+    p.pos = save;
+
+    let mut args = Vec::new();
+    loop {
+        p.skip_ws();
+        if p.peek().is_none() {
+            break;
+        }
+        args.push(p.parse_arg()?);
+    }
+    if args.is_empty() {
+        return Err(ParseError::EmptyCommand);
+    }
+    Ok(Step::Command { args })
+}
+
+/// Expand every literal and variable part of `expr` against `env`, without
+/// ever re-tokenizing the result.
+pub fn eval_expr(expr: &Expr, env: &BTreeMap<String, String>) -> Result<String, EvalError> {
+    let mut out = String::new();
+    for part in &expr.0 {
+        out.push_str(&eval_part(part, env)?);
+    }
+    Ok(out)
+}
+
+/// [`eval_expr`] over each argument of a parsed [`Step::Command`].
+pub fn eval_args(args: &[Expr], env: &BTreeMap<String, String>) -> Result<Vec<String>, EvalError> {
+    args.iter().map(|e| eval_expr(e, env)).collect()
+}
+
+fn eval_part(part: &ExprPart, env: &BTreeMap<String, String>) -> Result<String, EvalError> {
+    match part {
+        ExprPart::Literal(s) => Ok(s.clone()),
+        ExprPart::Var { name, op } => {
+            let value = env.get(name).filter(|v| !v.is_empty());
+            match op {
+                Some(VarOp::Default(default)) | Some(VarOp::Assign(default)) => match value {
+                    Some(v) => Ok(v.clone()),
+                    None => eval_expr(default, env),
+                },
+                Some(VarOp::Alternate(alt)) => match value {
+                    Some(_) => eval_expr(alt, env),
+                    None => Ok(String::new()),
+                },
+                None => env
+                    .get(name)
+                    .cloned()
+                    .ok_or_else(|| EvalError::MissingVar(name.clone())),
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(s: &'a str) -> Self {
+        Self { s, pos: 0 }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.s[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Consume `word` if it appears here as a whole word (followed by
+    /// whitespace or end of input), so a command that merely starts with
+    /// `cd` (e.g. `cdrkit-config`) isn't mistaken for the built-in.
+    fn eat_word(&mut self, word: &str) -> bool {
+        match self.rest().strip_prefix(word) {
+            Some(after) if after.chars().next().map_or(true, |c| c == ' ' || c == '\t') => {
+                self.pos += word.len();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn skip_ws(&mut self) {
+        while matches!(self.peek(), Some(' ') | Some('\t')) {
+            self.bump();
+        }
+    }
+
+    fn parse_ident(&mut self) -> Result<String, ParseError> {
+        let start = self.pos;
+        let mut out = String::new();
+        match self.peek() {
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                out.push(c);
+                self.bump();
+            }
+            _ => return Err(ParseError::ExpectedIdent(start)),
+        }
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                out.push(c);
+                self.bump();
+            } else {
+                break;
+            }
+        }
+        Ok(out)
+    }
+
+    /// A step-attribute name: like [`Self::parse_ident`] but allowing `-`,
+    /// since attributes are written `kebab-case` (`allow-network`).
+    fn parse_attr_name(&mut self) -> Result<String, ParseError> {
+        let start = self.pos;
+        let mut out = String::new();
+        match self.peek() {
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                out.push(c);
+                self.bump();
+            }
+            _ => return Err(ParseError::ExpectedIdent(start)),
+        }
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                out.push(c);
+                self.bump();
+            } else {
+                break;
+            }
+        }
+        Ok(out)
+    }
+
+    /// `$NAME` or `${NAME}` / `${NAME:-x}` / `${NAME:+x}` / `${NAME:=x}`,
+    /// with the leading `$` already consumed.
+    fn parse_interp(&mut self) -> Result<ExprPart, ParseError> {
+        if self.eat('{') {
+            let start = self.pos;
+            let name = self.parse_ident()?;
+            let op = if self.eat(':') {
+                let kind = self
+                    .bump()
+                    .ok_or(ParseError::UnexpectedEof("variable operator"))?;
+                let operand = self.parse_expr_until(|c| c == '}')?;
+                Some(match kind {
+                    '-' => VarOp::Default(operand),
+                    '+' => VarOp::Alternate(operand),
+                    '=' => VarOp::Assign(operand),
+                    other => return Err(ParseError::Unexpected(other, self.pos)),
+                })
+            } else {
+                None
+            };
+            if !self.eat('}') {
+                return Err(ParseError::Unterminated("${...}", start));
+            }
+            Ok(ExprPart::Var { name, op })
+        } else {
+            let name = self.parse_ident()?;
+            Ok(ExprPart::Var { name, op: None })
+        }
+    }
+
+    /// Literal text and interpolations, stopping (without consuming) at the
+    /// first unquoted character `stop` accepts, or at end of input.
+    /// Single-quoted text is taken verbatim; double-quoted text still
+    /// interpolates `$`/`${...}` and recognises `\` escapes, matching the
+    /// bash forms recipes already rely on.
+    fn parse_expr_until(&mut self, stop: impl Fn(char) -> bool) -> Result<Expr, ParseError> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        loop {
+            match self.peek() {
+                None => break,
+                Some(c) if stop(c) => break,
+                Some('\\') => {
+                    self.bump();
+                    let c = self
+                        .bump()
+                        .ok_or(ParseError::UnexpectedEof("escape sequence"))?;
+                    literal.push(c);
+                }
+                Some('$') => {
+                    self.bump();
+                    if !literal.is_empty() {
+                        parts.push(ExprPart::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(self.parse_interp()?);
+                }
+                Some('\'') => {
+                    self.bump();
+                    let start = self.pos;
+                    loop {
+                        match self.bump() {
+                            Some('\'') => break,
+                            Some(c) => literal.push(c),
+                            None => return Err(ParseError::Unterminated("'...'", start)),
+                        }
+                    }
+                }
+                Some('"') => {
+                    self.bump();
+                    let start = self.pos;
+                    loop {
+                        match self.peek() {
+                            Some('"') => {
+                                self.bump();
+                                break;
+                            }
+                            Some('\\') => {
+                                self.bump();
+                                let c = self
+                                    .bump()
+                                    .ok_or(ParseError::UnexpectedEof("escape sequence"))?;
+                                literal.push(c);
+                            }
+                            Some('$') => {
+                                self.bump();
+                                if !literal.is_empty() {
+                                    parts.push(ExprPart::Literal(std::mem::take(&mut literal)));
+                                }
+                                parts.push(self.parse_interp()?);
+                            }
+                            Some(c) => {
+                                literal.push(c);
+                                self.bump();
+                            }
+                            None => return Err(ParseError::Unterminated("\"...\"", start)),
+                        }
+                    }
+                }
+                Some(c) => {
+                    literal.push(c);
+                    self.bump();
+                }
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(ExprPart::Literal(literal));
+        }
+        Ok(Expr(parts))
+    }
+
+    /// One whitespace-delimited command/`cd` argument.
+    fn parse_arg(&mut self) -> Result<Expr, ParseError> {
+        self.parse_expr_until(|c| c == ' ' || c == '\t')
+    }
+}
+
+#[test]
+fn test_parse_attrs_none() {
+    let (attrs, rest) = parse_attrs("echo hi").unwrap();
+    assert_eq!(attrs, StepAttrs::default());
+    assert_eq!(rest, "echo hi");
+}
+
+#[test]
+fn test_parse_attrs_flags_and_values() {
+    let (attrs, rest) = parse_attrs("[allow-network][retry=3][timeout=30] curl -O $URL").unwrap();
+    assert!(attrs.allow_network);
+    assert!(!attrs.no_chroot);
+    assert_eq!(attrs.retry, 3);
+    assert_eq!(attrs.timeout_secs, Some(30));
+    assert_eq!(rest, "curl -O $URL");
+}
+
+#[test]
+fn test_parse_attrs_unknown() {
+    assert!(matches!(
+        parse_attrs("[bogus] echo hi"),
+        Err(ParseError::UnknownAttribute(name, _)) if name == "bogus"
+    ));
+}
+
+#[test]
+fn test_parse_step_cd() {
+    assert_eq!(
+        parse_step("cd /some/dir").unwrap(),
+        Step::Cd {
+            path: Expr(vec![ExprPart::Literal("/some/dir".to_owned())])
+        }
+    );
+}
+
+#[test]
+fn test_parse_step_assignment() {
+    let step = parse_step("FOO=bar baz").unwrap();
+    match step {
+        Step::Assignment { name, value } => {
+            assert_eq!(name, "FOO");
+            assert_eq!(
+                eval_expr(&value, &BTreeMap::new()).unwrap(),
+                "bar baz".to_owned()
+            );
+        }
+        other => panic!("expected Assignment, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_parse_step_command_with_interpolation_and_quoting() {
+    let step = parse_step("echo \"hello $NAME\" 'literal $NAME'").unwrap();
+    let env = BTreeMap::from([("NAME".to_owned(), "world".to_owned())]);
+    match step {
+        Step::Command { args } => {
+            let evaled = eval_args(&args, &env).unwrap();
+            assert_eq!(evaled, vec!["echo", "hello world", "literal $NAME"]);
+        }
+        other => panic!("expected Command, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_cd_is_not_mistaken_for_prefix() {
+    let step = parse_step("cdrkit-config --version").unwrap();
+    match step {
+        Step::Command { args } => assert_eq!(args.len(), 2),
+        other => panic!("expected Command, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_eval_expr_var_ops() {
+    let env = BTreeMap::from([("SET".to_owned(), "x".to_owned())]);
+    let step =
+        parse_step("echo ${UNSET:-default} ${SET:-default} ${SET:+alt} ${UNSET:+alt}").unwrap();
+    match step {
+        Step::Command { args } => {
+            assert_eq!(
+                eval_args(&args, &env).unwrap(),
+                vec!["echo", "default", "x", "alt", ""]
+            );
+        }
+        other => panic!("expected Command, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_eval_expr_missing_var_errors() {
+    let step = parse_step("echo $MISSING").unwrap();
+    match step {
+        Step::Command { args } => {
+            assert!(matches!(
+                eval_args(&args, &BTreeMap::new()),
+                Err(EvalError::MissingVar(name)) if name == "MISSING"
+            ));
+        }
+        other => panic!("expected Command, got {:?}", other),
+    }
+}
@@ -10,12 +10,163 @@ pub fn source_path(hash: &str) -> PathBuf {
         .join(hash)
 }
 
-pub fn fetch_source(source: &SourceContents) -> Vec<u8> {
-    println!("Downloading {}",source.url);
-    let source_data = reqwest::blocking::get(&source.url).unwrap().bytes().unwrap();
-    assert_eq!(source.sha, sha256::digest(&*source_data));
-    let store_path = source_path(&source.sha);
+/// Split a (possibly algorithm-tagged) declared digest into `(algorithm, hex)`.
+/// A bare 64-hex-character string is treated as `sha256` for backward
+/// compatibility with existing `sources.yaml` entries.
+fn parse_tagged_digest(tagged: &str) -> (&str, &str) {
+    match tagged.split_once(':') {
+        Some((algo, hex)) => (algo, hex),
+        None => ("sha256", tagged),
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn digest_hex(algo: &str, data: &[u8]) -> Option<String> {
+    match algo {
+        "sha256" => Some(sha256::digest(data)),
+        "sha512" => {
+            use sha2::{Digest, Sha512};
+            Some(to_hex(&Sha512::digest(data)))
+        }
+        "blake3" => Some(blake3::hash(data).to_hex().to_string()),
+        _ => None,
+    }
+}
+
+fn digest_matches(tagged: &str, data: &[u8]) -> bool {
+    let (algo, hex) = parse_tagged_digest(tagged);
+    digest_hex(algo, data).is_some_and(|d| d == hex)
+}
+
+/// Maps a source's declared, non-sha256 digest to the canonical sha256 we key
+/// `build-cache` entries by, so the on-disk cache layout stays stable no
+/// matter which algorithm a recipe author pinned.
+fn canonical_hash_db() -> sled::Db {
+    sled::open("source-hash.sled").unwrap()
+}
+
+/// Where a source's bytes live on disk under its canonical sha256. A
+/// `sha256` digest always maps to a path, whether or not the file has been
+/// fetched yet; any other algorithm's digest yields `None` until a fetch
+/// has recorded its canonical sha256.
+pub fn resolved_source_path(source: &SourceContents) -> Option<PathBuf> {
+    let (algo, hex) = parse_tagged_digest(&source.sha);
+    if algo == "sha256" {
+        return Some(source_path(hex));
+    }
+    let canonical = canonical_hash_db().get(&source.sha).ok()??;
+    Some(source_path(&String::from_utf8(canonical.to_vec()).ok()?))
+}
+
+fn store_fetched(source: &SourceContents, data: &[u8]) -> PathBuf {
+    let canonical = sha256::digest(data);
+    let store_path = source_path(&canonical);
     std::fs::create_dir_all(store_path.parent().unwrap()).unwrap();
-    std::fs::write(store_path,&source_data).unwrap();
-    source_data.to_vec()
-}
\ No newline at end of file
+    std::fs::write(&store_path, data).unwrap();
+    let (algo, _) = parse_tagged_digest(&source.sha);
+    if algo != "sha256" {
+        canonical_hash_db()
+            .insert(&source.sha, canonical.as_str())
+            .unwrap();
+    }
+    store_path
+}
+
+/// Remote substituter endpoints to consult before doing any real work,
+/// configured as a comma-separated list in `BOOTSTRAP_CACHE_URLS`. Each entry
+/// is queried as `<cache>/source/<algo>-<hex>` for sources and
+/// `<cache>/artefact/<equiv-hash>` for built packages.
+pub fn cache_endpoints() -> Vec<String> {
+    std::env::var("BOOTSTRAP_CACHE_URLS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+fn cache_key(source: &SourceContents) -> String {
+    let (algo, hex) = parse_tagged_digest(&source.sha);
+    format!("{algo}-{hex}")
+}
+
+fn try_fetch_cached_source(cache: &str, source: &SourceContents) -> Option<Vec<u8>> {
+    let url = format!(
+        "{}/source/{}",
+        cache.trim_end_matches('/'),
+        cache_key(source)
+    );
+    let resp = reqwest::blocking::get(&url).ok()?;
+    if !resp.status().is_success() {
+        return None;
+    }
+    let data = resp.bytes().ok()?.to_vec();
+    if digest_matches(&source.sha, &data) {
+        Some(data)
+    } else {
+        None
+    }
+}
+
+/// Upload a freshly-fetched source to every configured cache, keyed by its
+/// declared digest, so the next node to need it can skip the upstream
+/// round-trip (and every mirror probe).
+pub fn push_source(source: &SourceContents, data: &[u8]) {
+    for cache in cache_endpoints() {
+        let url = format!(
+            "{}/source/{}",
+            cache.trim_end_matches('/'),
+            cache_key(source)
+        );
+        let _ = reqwest::blocking::Client::new()
+            .put(url)
+            .body(data.to_vec())
+            .send();
+    }
+}
+
+/// Fetch a source's bytes, verifying them against whichever digest algorithm
+/// was declared. Tries, in order: the local `build-cache`, every configured
+/// remote substituter, then `url` followed by each configured mirror.
+pub fn fetch_source(source: &SourceContents) -> Vec<u8> {
+    if let Some(path) = resolved_source_path(source) {
+        if let Ok(data) = std::fs::read(&path) {
+            if digest_matches(&source.sha, &data) {
+                return data;
+            }
+        }
+    }
+
+    for cache in cache_endpoints() {
+        if let Some(data) = try_fetch_cached_source(&cache, source) {
+            println!("Fetched {} from cache {}", source.sha, cache);
+            store_fetched(source, &data);
+            return data;
+        }
+    }
+
+    for url in source.urls() {
+        println!("Downloading {url}");
+        let Ok(resp) = reqwest::blocking::get(url) else {
+            continue;
+        };
+        let Ok(data) = resp.bytes() else {
+            continue;
+        };
+        if digest_matches(&source.sha, &data) {
+            store_fetched(source, &data);
+            push_source(source, &data);
+            return data.to_vec();
+        }
+        println!("  digest mismatch from {url}, trying next mirror");
+    }
+
+    panic!(
+        "could not fetch source {} ({}) from any mirror",
+        source.url, source.sha
+    );
+}